@@ -0,0 +1,376 @@
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::error::PianoError;
+use crate::metronome::PULSES_PER_BEAT;
+use crate::piano_listen::{ Percent, PianoEvent, PianoKeyCode, Velocity };
+
+/// Order the arpeggiator plays the held notes in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArpPattern {
+    #[default]
+    Up,
+    Down,
+    /// Up and back down, without repeating the top and bottom notes
+    UpDown,
+    Random,
+    /// In the order the keys were pressed
+    AsPlayed,
+}
+
+/// What the arpeggiator keeps time with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ArpSync {
+    /// The internal metronome, at this many beats per minute
+    Internal(f32),
+    /// MIDI clock coming in on any of the inputs
+    MidiClock,
+}
+
+impl Default for ArpSync {
+    fn default() -> Self {
+        Self::Internal(120.0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArpSettings {
+    pub pattern: ArpPattern,
+    /// How many octaves (1 to 4) the held notes are repeated over
+    pub octaves: u8,
+    /// Steps per beat, one that splits the clock pulses of a beat evenly (1, 2, 3, 4, 6, 8, 12 or 24)
+    pub division: u8,
+    /// Share of a step each note sounds for, between 0 and 1
+    pub gate: f32,
+    /// Keeps playing the last chord after its keys are let go, until a new one is played
+    pub latch: bool,
+    pub sync: ArpSync,
+    /// Channel (0 to 15) the notes are sent on
+    pub channel: u8,
+}
+
+impl Default for ArpSettings {
+    fn default() -> Self {
+        Self {
+            pattern: ArpPattern::Up,
+            octaves: 1,
+            division: 4,
+            gate: 0.5,
+            latch: false,
+            sync: ArpSync::default(),
+            channel: 0,
+        }
+    }
+}
+
+impl ArpSettings {
+    pub fn validate(&self) -> Result<(), PianoError> {
+        if !(1..=4).contains(&self.octaves) {
+            return Err(PianoError::Arpeggiator("octaves must be between 1 and 4".to_string()));
+        }
+        if self.division == 0 || !PULSES_PER_BEAT.is_multiple_of(self.division as u32) {
+            return Err(PianoError::Arpeggiator(format!("{} steps don't fit a beat", self.division)));
+        }
+        if !(0.0..=1.0).contains(&self.gate) {
+            return Err(PianoError::Arpeggiator("gate must be between 0 and 1".to_string()));
+        }
+        if let ArpSync::Internal(bpm) = self.sync {
+            if bpm.is_nan() || bpm <= 0.0 {
+                return Err(PianoError::Arpeggiator("tempo must be above 0".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns the keys being held into a repeating pattern of notes, one step at a time.
+///
+/// The arpeggiator is fed the key presses and releases of the inputs and advanced by clock
+/// pulses, at `PULSES_PER_BEAT`. It only works out which notes start and stop, sending them on is
+/// up to the caller.
+#[derive(Debug)]
+pub struct Arpeggiator {
+    settings: ArpSettings,
+    /// Keys held (or latched), in the order they were pressed
+    held: Vec<(PianoKeyCode, Percent, Velocity)>,
+    /// Keys physically down, which with latch on can be fewer than `held`
+    down: Vec<u8>,
+    sounding: Option<PianoKeyCode>,
+    pulse: u32,
+    step: usize,
+    seed: u64,
+}
+
+impl Arpeggiator {
+    pub fn new(settings: ArpSettings) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Self {
+            settings,
+            held: Vec::new(),
+            down: Vec::new(),
+            sounding: None,
+            pulse: 0,
+            step: 0,
+            seed: seed | 1,
+        }
+    }
+
+    pub fn settings(&self) -> &ArpSettings {
+        &self.settings
+    }
+
+    /// Takes in an event, returning whether it was a key press or release. Those are played by
+    /// the arpeggiator, so they shouldn't be played directly as well.
+    pub fn feed(&mut self, event: &PianoEvent) -> bool {
+        match *event {
+            PianoEvent::KeyPress(key, percent, velocity) if velocity.raw > 0 => {
+                // With latch on, the first key of a new chord lets go of the latched one
+                if self.settings.latch && self.down.is_empty() {
+                    self.held.clear();
+                }
+                self.held.retain(|(held, _, _)| (*held as u8) != (key as u8));
+                self.held.push((key, percent, velocity));
+                self.down.push(key as u8);
+                true
+            }
+            // A note-on without velocity is a release
+            PianoEvent::KeyPress(key, _, _) | PianoEvent::KeyRelease(key) => {
+                self.down.retain(|down| *down != (key as u8));
+                if !self.settings.latch {
+                    self.held.retain(|(held, _, _)| (*held as u8) != (key as u8));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Advances by one clock pulse and returns the notes that start or stop on it.
+    pub fn pulse(&mut self) -> Vec<PianoEvent> {
+        let pulses_per_step = PULSES_PER_BEAT / (self.settings.division.max(1) as u32);
+        let gate = ((self.settings.gate * (pulses_per_step as f32)).round() as u32).clamp(1, pulses_per_step);
+        let position = self.pulse % pulses_per_step;
+        self.pulse = self.pulse.wrapping_add(1);
+
+        let mut events = Vec::new();
+        // With the gate fully open a note lasts until the next one starts
+        if position == 0 || position == gate {
+            events.extend(self.sounding.take().map(PianoEvent::KeyRelease));
+        }
+        if position != 0 {
+            return events;
+        }
+
+        let sequence = self.sequence();
+        if sequence.is_empty() {
+            self.step = 0;
+            return events;
+        }
+        let index = match self.settings.pattern {
+            ArpPattern::Random => self.random() % sequence.len(),
+            _ => self.step % sequence.len(),
+        };
+        self.step = self.step.wrapping_add(1);
+
+        let (key, percent, velocity) = sequence[index];
+        events.push(PianoEvent::KeyPress(key, percent, velocity));
+        self.sounding = Some(key);
+
+        events
+    }
+
+    /// Moves to `pulse` clock pulses from the start of the song, when the transport starts over or
+    /// seeks, so the steps stay in time with it.
+    pub fn seek(&mut self, pulse: u32) {
+        let pulses_per_step = PULSES_PER_BEAT / (self.settings.division.max(1) as u32);
+        self.pulse = pulse;
+        self.step = (pulse / pulses_per_step) as usize;
+    }
+
+    /// Releases the note that is sounding, if any, for when the arpeggiator is stopped.
+    pub fn stop(&mut self) -> Vec<PianoEvent> {
+        self.sounding.take().map(PianoEvent::KeyRelease).into_iter().collect()
+    }
+
+    // One cycle of the pattern over the held keys and their octaves
+    fn sequence(&self) -> Vec<(PianoKeyCode, Percent, Velocity)> {
+        let mut notes = self.held.clone();
+        if self.settings.pattern != ArpPattern::AsPlayed {
+            notes.sort_by_key(|(key, _, _)| *key as u8);
+        }
+
+        let mut sequence = Vec::new();
+        for octave in 0..self.settings.octaves.clamp(1, 4) {
+            for (key, percent, velocity) in &notes {
+                // Octaves past the top of the keyboard are left out
+                if let Some(key) = PianoKeyCode::checked((*key as u8) + 12 * octave) {
+                    sequence.push((key, *percent, *velocity));
+                }
+            }
+        }
+
+        match self.settings.pattern {
+            ArpPattern::Down => sequence.reverse(),
+            ArpPattern::UpDown => {
+                let down: Vec<_> = sequence
+                    .iter()
+                    .rev()
+                    .skip(1)
+                    .take(sequence.len().saturating_sub(2))
+                    .copied()
+                    .collect();
+                sequence.extend(down);
+            }
+            _ => {}
+        }
+
+        sequence
+    }
+
+    // Xorshift, good enough to pick notes with
+    fn random(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::velocity::VelocityCurve;
+
+    fn press(note: u8) -> PianoEvent {
+        let velocity = Velocity::new(100);
+        let percent = Percent::with_curve(velocity, &VelocityCurve::default());
+        PianoEvent::KeyPress(PianoKeyCode::try_from(note).unwrap(), percent, velocity)
+    }
+
+    fn release(note: u8) -> PianoEvent {
+        PianoEvent::KeyRelease(PianoKeyCode::try_from(note).unwrap())
+    }
+
+    fn arpeggiator(pattern: ArpPattern, octaves: u8, held: &[u8]) -> Arpeggiator {
+        let mut arpeggiator = Arpeggiator::new(ArpSettings { pattern, octaves, ..Default::default() });
+        for note in held {
+            arpeggiator.feed(&press(*note));
+        }
+        arpeggiator
+    }
+
+    // What happens on each pulse, as notes started (positive) and stopped (negative)
+    fn pulses(arpeggiator: &mut Arpeggiator, count: u32) -> Vec<Vec<i16>> {
+        (0..count)
+            .map(|_| {
+                arpeggiator
+                    .pulse()
+                    .iter()
+                    .map(|piano_event| match piano_event {
+                        PianoEvent::KeyPress(key, _, _) => *key as i16,
+                        PianoEvent::KeyRelease(key) => -(*key as i16),
+                        piano_event => panic!("{:?}", piano_event),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // The notes started over `steps` steps
+    fn played(arpeggiator: &mut Arpeggiator, steps: u32) -> Vec<i16> {
+        let pulses_per_step = PULSES_PER_BEAT / (arpeggiator.settings().division as u32);
+        pulses(arpeggiator, steps * pulses_per_step)
+            .into_iter()
+            .flatten()
+            .filter(|note| *note > 0)
+            .collect()
+    }
+
+    #[test]
+    fn plays_the_held_notes_in_pattern_order() {
+        let held = [64, 60, 67];
+
+        assert_eq!(played(&mut arpeggiator(ArpPattern::Up, 1, &held), 6), [60, 64, 67, 60, 64, 67]);
+        assert_eq!(played(&mut arpeggiator(ArpPattern::Down, 1, &held), 4), [67, 64, 60, 67]);
+        assert_eq!(played(&mut arpeggiator(ArpPattern::UpDown, 1, &held), 6), [60, 64, 67, 64, 60, 64]);
+        assert_eq!(played(&mut arpeggiator(ArpPattern::AsPlayed, 1, &held), 4), [64, 60, 67, 64]);
+    }
+
+    #[test]
+    fn repeats_the_notes_over_the_octaves() {
+        assert_eq!(played(&mut arpeggiator(ArpPattern::Up, 2, &[64, 60]), 4), [60, 64, 72, 76]);
+        assert_eq!(played(&mut arpeggiator(ArpPattern::UpDown, 2, &[60, 64]), 6), [60, 64, 72, 76, 72, 64]);
+        // Octaves past the top of the keyboard are left out
+        assert_eq!(played(&mut arpeggiator(ArpPattern::Up, 3, &[100]), 3), [100, 112, 100]);
+    }
+
+    #[test]
+    fn random_only_plays_held_notes() {
+        let notes = played(&mut arpeggiator(ArpPattern::Random, 2, &[60, 64]), 50);
+
+        assert_eq!(notes.len(), 50);
+        assert!(notes.iter().all(|note| [60, 64, 72, 76].contains(note)), "{:?}", notes);
+    }
+
+    #[test]
+    fn gate_sets_how_long_each_note_sounds() {
+        // Six pulses a step at the default four steps a beat
+        let mut half = arpeggiator(ArpPattern::Up, 1, &[60, 64]);
+        let expected: Vec<Vec<i16>> = vec![vec![60], vec![], vec![], vec![-60], vec![], vec![], vec![64]];
+        assert_eq!(pulses(&mut half, 7), expected);
+
+        let mut open = arpeggiator(ArpPattern::Up, 1, &[60, 64]);
+        open.settings.gate = 1.0;
+        assert_eq!(pulses(&mut open, 7)[6], [-60, 64]);
+
+        // A closed gate still lets a note sound for a pulse
+        let mut closed = arpeggiator(ArpPattern::Up, 1, &[60]);
+        closed.settings.gate = 0.0;
+        assert_eq!(pulses(&mut closed, 2), [vec![60], vec![-60]]);
+    }
+
+    #[test]
+    fn stops_once_the_keys_are_let_go() {
+        let mut arpeggiator = arpeggiator(ArpPattern::Up, 1, &[60]);
+        assert_eq!(pulses(&mut arpeggiator, 1), [[60]]);
+
+        arpeggiator.feed(&release(60));
+        assert!(played(&mut arpeggiator, 4).is_empty());
+        assert!(arpeggiator.stop().is_empty());
+    }
+
+    #[test]
+    fn latch_holds_the_chord_until_a_new_one() {
+        let mut arpeggiator = Arpeggiator::new(ArpSettings { latch: true, ..Default::default() });
+        for piano_event in [press(60), press(64), release(60), release(64)] {
+            assert!(arpeggiator.feed(&piano_event));
+        }
+        assert_eq!(played(&mut arpeggiator, 3), [60, 64, 60]);
+
+        arpeggiator.feed(&press(67));
+        assert_eq!(played(&mut arpeggiator, 2), [67, 67]);
+    }
+
+    #[test]
+    fn rejects_settings_it_cannot_play() {
+        let invalid = [
+            ArpSettings { octaves: 5, ..Default::default() },
+            ArpSettings { division: 5, ..Default::default() },
+            ArpSettings { division: 0, ..Default::default() },
+            ArpSettings { gate: 1.5, ..Default::default() },
+            ArpSettings { sync: ArpSync::Internal(0.0), ..Default::default() },
+            ArpSettings { sync: ArpSync::Internal(f32::NAN), ..Default::default() },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err(), "{:?}", settings);
+        }
+        assert!(ArpSettings::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,182 @@
+use std::ops::Deref;
+use std::sync::{ Arc, OnceLock };
+
+use midir::{ Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection };
+
+use crate::error::PianoError;
+use crate::virtual_ports::{ self, VirtualPorts, CLIENT_NAME, VIRTUAL_INPUT, VIRTUAL_OUTPUT };
+
+/// Called with every message arriving on an input, along with its timestamp in microseconds.
+pub type InputCallback = Box<dyn FnMut(u64, &[u8]) + Send + 'static>;
+
+/// An open input, which stops calling its callback once closed or dropped.
+pub trait InputConnection: Send {
+    fn close(self: Box<Self>);
+}
+
+/// An open output.
+pub trait OutputConnection: Send {
+    fn send(&mut self, message: &[u8]) -> Result<(), PianoError>;
+
+    fn close(self: Box<Self>);
+}
+
+/// Where MIDI comes from and goes to: finding ports, listening to inputs and sending to outputs.
+///
+/// The app runs on `MidirBackend`, while tests swap in an in-memory backend so the listener and
+/// playback can be driven without hardware.
+pub trait MidiBackend: Send + Sync + 'static {
+    fn input_ports(&self) -> Result<Vec<String>, PianoError>;
+
+    fn output_ports(&self) -> Result<Vec<String>, PianoError>;
+
+    fn connect_input(
+        &self,
+        name: &str,
+        callback: InputCallback
+    ) -> Result<Box<dyn InputConnection>, PianoError>;
+
+    fn connect_output(&self, name: &str) -> Result<Box<dyn OutputConnection>, PianoError>;
+}
+
+/// The backend in use, shared by everything that opens ports. Defaults to midir.
+#[derive(Clone)]
+pub struct Backend(Arc<dyn MidiBackend>);
+
+impl Backend {
+    pub fn new<B: MidiBackend>(backend: B) -> Self {
+        Self(Arc::new(backend))
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::new(MidirBackend::new())
+    }
+}
+
+impl Deref for Backend {
+    type Target = dyn MidiBackend;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+/// The system's MIDI ports through midir, along with the app's own virtual ports where the
+/// platform has them.
+pub struct MidirBackend {
+    // Created on first use, unless asked for up front
+    virtual_ports: OnceLock<VirtualPorts>,
+}
+
+impl MidirBackend {
+    pub fn new() -> Self {
+        Self { virtual_ports: OnceLock::new() }
+    }
+
+    /// A backend whose virtual ports are there right away, for other software to find before the
+    /// app uses them.
+    pub fn with_virtual_ports() -> Self {
+        let backend = Self::new();
+        backend.virtual_ports();
+        backend
+    }
+
+    fn virtual_ports(&self) -> &VirtualPorts {
+        self.virtual_ports.get_or_init(VirtualPorts::create)
+    }
+}
+
+impl Default for MidirBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiBackend for MidirBackend {
+    fn input_ports(&self) -> Result<Vec<String>, PianoError> {
+        let midi_in = MidiInput::new("midir port listing")?;
+        let mut names: Vec<String> = midi_in
+            .ports()
+            .iter()
+            .filter_map(|port| midi_in.port_name(port).ok())
+            .filter(|name| !virtual_ports::is_own_port(name))
+            .collect();
+        // Open for as long as the app runs, so it is always there to pick
+        if virtual_ports::SUPPORTED {
+            names.push(VIRTUAL_INPUT.to_string());
+        }
+
+        Ok(names)
+    }
+
+    fn output_ports(&self) -> Result<Vec<String>, PianoError> {
+        let midi_out = MidiOutput::new("midir port listing")?;
+        let mut names: Vec<String> = midi_out
+            .ports()
+            .iter()
+            .filter_map(|port| midi_out.port_name(port).ok())
+            .filter(|name| !virtual_ports::is_own_port(name))
+            .collect();
+        // Open for as long as the app runs, so it is always there to pick
+        if virtual_ports::SUPPORTED {
+            names.push(VIRTUAL_OUTPUT.to_string());
+        }
+
+        Ok(names)
+    }
+
+    fn connect_input(
+        &self,
+        name: &str,
+        mut callback: InputCallback
+    ) -> Result<Box<dyn InputConnection>, PianoError> {
+        if name == VIRTUAL_INPUT {
+            return self.virtual_ports().connect_input(callback);
+        }
+        let callback = move |stamp, message: &[u8], _: &mut ()| callback(stamp, message);
+
+        let mut midi_in = MidiInput::new(CLIENT_NAME)?;
+        // Clock and SysEx (MTC full frames) are wanted too
+        midi_in.ignore(Ignore::None);
+        let in_port = midi_in
+            .ports()
+            .into_iter()
+            .find(|port| midi_in.port_name(port).as_deref() == Ok(name))
+            .ok_or_else(|| PianoError::InvalidPort(name.to_string()))?;
+
+        Ok(Box::new(midi_in.connect(&in_port, "midir-read-input", callback, ())?))
+    }
+
+    fn connect_output(&self, name: &str) -> Result<Box<dyn OutputConnection>, PianoError> {
+        if name == VIRTUAL_OUTPUT {
+            return self.virtual_ports().connect_output();
+        }
+
+        let midi_out = MidiOutput::new(CLIENT_NAME)?;
+        let out_port = midi_out
+            .ports()
+            .into_iter()
+            .find(|port| midi_out.port_name(port).as_deref() == Ok(name))
+            .ok_or_else(|| PianoError::InvalidPort(name.to_string()))?;
+
+        Ok(Box::new(midi_out.connect(&out_port, "midir-output")?))
+    }
+}
+
+impl InputConnection for MidiInputConnection<()> {
+    fn close(self: Box<Self>) {
+        MidiInputConnection::close(*self);
+    }
+}
+
+impl OutputConnection for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) -> Result<(), PianoError> {
+        Ok(MidiOutputConnection::send(self, message)?)
+    }
+
+    fn close(self: Box<Self>) {
+        MidiOutputConnection::close(*self);
+    }
+}
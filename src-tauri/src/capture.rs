@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::recording::{ Recording, SourceId };
+
+// Upper bound on buffered messages, so a flood of controller data can't grow the buffer unbounded
+const MAX_MESSAGES: usize = 200_000;
+
+/// Rolling buffer holding the raw MIDI received during the last `window`.
+///
+/// Messages are stamped with their time since listening started, like the chunks of a `Recording`.
+/// The buffer outlives a single listener, so the time between two listeners is left out: every
+/// listener adds its own offset, from `resume`, to the times of its messages.
+#[derive(Debug)]
+pub struct CaptureBuffer {
+    window: Duration,
+    sources: Vec<String>,
+    messages: VecDeque<(Duration, SourceId, Vec<u8>)>,
+}
+
+impl CaptureBuffer {
+    pub fn new(window: Duration) -> Self {
+        Self { window, sources: Vec::new(), messages: VecDeque::new() }
+    }
+
+    /// The id of the source called `name`, which is added if it isn't known yet.
+    pub fn source(&mut self, name: &str) -> SourceId {
+        match self.sources.iter().position(|source| source == name) {
+            Some(id) => id as SourceId,
+            None => {
+                self.sources.push(name.to_string());
+                (self.sources.len() - 1) as SourceId
+            }
+        }
+    }
+
+    /// The offset that continues the buffer for a listener whose times start over from zero. Each
+    /// listener keeps its own, so starting one doesn't move the messages of another.
+    pub fn resume(&self) -> Duration {
+        self.messages
+            .back()
+            .map(|(at, _, _)| *at)
+            .unwrap_or_default()
+    }
+
+    /// Adds a message at `at`, a time a listener got by adding its offset to its own time.
+    pub fn push(&mut self, at: Duration, source: SourceId, message: Vec<u8>) {
+        // Messages from different devices can arrive slightly out of order
+        match self.messages.back() {
+            Some((last, _, _)) if *last > at => {
+                let index = self.messages.partition_point(|(time, _, _)| *time <= at);
+                self.messages.insert(index, (at, source, message));
+            }
+            _ => self.messages.push_back((at, source, message)),
+        }
+
+        while self.messages.len() > MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        while let Some((oldest, _, _)) = self.messages.front() {
+            if at.saturating_sub(*oldest) <= self.window {
+                break;
+            }
+            self.messages.pop_front();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Turns the buffered messages into a `Recording`, starting at the oldest message.
+    pub fn to_recording(&self) -> Recording {
+        let mut recording = Recording::from(self.sources.clone(), Vec::new());
+        let start = match self.messages.front() {
+            Some((at, _, _)) => *at,
+            None => {
+                return recording;
+            }
+        };
+
+        for (at, source, message) in &self.messages {
+            recording.push((at.saturating_sub(start), *source, message.clone()));
+        }
+
+        recording
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn times(buffer: &CaptureBuffer) -> Vec<Duration> {
+        buffer.messages.iter().map(|(at, _, _)| *at).collect()
+    }
+
+    #[test]
+    fn a_new_listener_does_not_move_a_running_one() {
+        let mut buffer = CaptureBuffer::new(Duration::from_secs(60));
+        let first = buffer.resume();
+        buffer.push(first + ms(10), 0, vec![0x90, 60, 100]);
+
+        let second = buffer.resume();
+        buffer.push(first + ms(20), 0, vec![0x80, 60, 0]);
+        buffer.push(second + ms(5), 1, vec![0x90, 64, 100]);
+
+        assert_eq!(times(&buffer), vec![ms(10), ms(15), ms(20)]);
+    }
+
+    #[test]
+    fn keeps_only_the_last_window() {
+        let mut buffer = CaptureBuffer::new(ms(100));
+        for at in [0, 40, 80, 120, 160] {
+            buffer.push(ms(at), 0, vec![0x90, 60, 100]);
+        }
+
+        assert_eq!(times(&buffer), vec![ms(80), ms(120), ms(160)]);
+    }
+
+    #[test]
+    fn sorts_late_messages_into_place() {
+        let mut buffer = CaptureBuffer::new(ms(100));
+        buffer.push(ms(10), 0, vec![0x90, 60, 100]);
+        buffer.push(ms(30), 0, vec![0x80, 60, 0]);
+        buffer.push(ms(20), 1, vec![0x90, 64, 100]);
+
+        assert_eq!(times(&buffer), vec![ms(10), ms(20), ms(30)]);
+    }
+
+    #[test]
+    fn recording_starts_at_the_oldest_message() {
+        let mut buffer = CaptureBuffer::new(ms(100));
+        let keys = buffer.source("Keys");
+        buffer.push(ms(500), keys, vec![0x90, 60, 100]);
+        buffer.push(ms(750), keys, vec![0x80, 60, 0]);
+
+        let recording = buffer.to_recording();
+
+        // The first message fell out of the window
+        assert_eq!(recording.recording, vec![(Duration::ZERO, keys, vec![0x80, 60, 0])]);
+        assert_eq!(recording.sources, ["Keys"]);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::error::PianoError;
+use crate::piano_listen::{ PianoEvent, PianoKeyCode };
+
+/// A chord shape, as the distance in semitones of each of its notes from the key that plays it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Voicing {
+    pub name: String,
+    pub intervals: Vec<u8>,
+    /// Pitch classes (0 for C up to 11 for B) of the keys that play this voicing, every key if empty
+    #[serde(default)]
+    pub keys: Vec<u8>,
+}
+
+impl Voicing {
+    /// The voicing of the chord `held`, counted from its lowest note. `None` if nothing is held.
+    pub fn from_held(name: String, held: &[u8], keys: Vec<u8>) -> Option<Self> {
+        let lowest = *held.iter().min()?;
+        let mut intervals: Vec<u8> = held
+            .iter()
+            .map(|note| note - lowest)
+            .collect();
+        intervals.sort_unstable();
+        intervals.dedup();
+
+        Some(Self { name, intervals, keys: keys.into_iter().map(|key| key % 12).collect() })
+    }
+}
+
+/// Voicings kept together, e.g. the chords of one song, which can be saved and loaded as a preset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChordSet {
+    pub name: String,
+    pub voicings: Vec<Voicing>,
+}
+
+impl ChordSet {
+    /// The voicing `key` plays: the first one claiming its pitch class, or else the first one for
+    /// every key.
+    pub fn voicing_for(&self, key: u8) -> Option<&Voicing> {
+        self.voicings
+            .iter()
+            .find(|voicing| voicing.keys.contains(&(key % 12)))
+            .or_else(|| self.voicings.iter().find(|voicing| voicing.keys.is_empty()))
+    }
+
+    /// Adds a voicing, replacing any with the same name.
+    pub fn insert(&mut self, voicing: Voicing) {
+        match self.voicings.iter_mut().find(|known| known.name == voicing.name) {
+            Some(known) => {
+                *known = voicing;
+            }
+            None => self.voicings.push(voicing),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    enabled: bool,
+    chord_set: ChordSet,
+    /// Keys held on any input, in the order they were pressed
+    held: Vec<u8>,
+}
+
+/// The chord memory shared between the commands and the listeners.
+///
+/// While it is enabled, every key plays the voicing the current set has for it, moved to start on
+/// that key. It always keeps track of the keys being held, so their chord can be captured.
+#[derive(Debug, Clone, Default)]
+pub struct ChordMemory(Arc<Mutex<MemoryState>>);
+
+impl ChordMemory {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.lock().enabled = enabled;
+    }
+
+    pub fn chord_set(&self) -> ChordSet {
+        self.lock().chord_set.clone()
+    }
+
+    pub fn set_chord_set(&self, chord_set: ChordSet) {
+        self.lock().chord_set = chord_set;
+    }
+
+    /// Stores the chord being held as a voicing of the current set, played by `keys` (pitch
+    /// classes, every key if empty).
+    pub fn capture(&self, name: String, keys: Vec<u8>) -> Result<Voicing, PianoError> {
+        let mut state = self.lock();
+        let voicing = Voicing::from_held(name, &state.held, keys).ok_or_else(|| {
+            PianoError::Chord("no keys are held".to_string())
+        })?;
+        state.chord_set.insert(voicing.clone());
+
+        Ok(voicing)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Plays the key presses of one input as the chords in memory.
+///
+/// Every key remembers the notes it started, so letting go of it stops exactly those, even if the
+/// memory changed in the meantime.
+#[derive(Debug)]
+pub struct ChordPlayer {
+    memory: ChordMemory,
+    sounding: HashMap<u8, Vec<PianoKeyCode>>,
+}
+
+impl ChordPlayer {
+    pub fn new(memory: ChordMemory) -> Self {
+        Self { memory, sounding: HashMap::new() }
+    }
+
+    /// The notes `piano_event` plays instead of itself, `None` if it plays as it is.
+    pub fn play(&mut self, piano_event: &PianoEvent) -> Option<Vec<PianoEvent>> {
+        match *piano_event {
+            PianoEvent::KeyPress(key, percent, velocity) if velocity.raw > 0 => {
+                let mut state = self.memory.lock();
+                state.held.retain(|held| *held != (key as u8));
+                state.held.push(key as u8);
+                if !state.enabled {
+                    return None;
+                }
+
+                let voicing = state.chord_set.voicing_for(key as u8)?;
+                let notes: Vec<PianoKeyCode> = voicing.intervals
+                    .iter()
+                    .filter_map(|interval| PianoKeyCode::checked((key as u8).saturating_add(*interval)))
+                    .collect();
+                drop(state);
+
+                // A key struck again while its chord still sounds starts that chord over
+                let mut piano_events = self.release(key as u8);
+                piano_events.extend(
+                    notes.iter().map(|note| PianoEvent::KeyPress(*note, percent, velocity))
+                );
+                self.sounding.insert(key as u8, notes);
+                Some(piano_events)
+            }
+            // A note-on without velocity is a release
+            PianoEvent::KeyPress(key, _, _) | PianoEvent::KeyRelease(key) => {
+                self.memory.lock().held.retain(|held| *held != (key as u8));
+                self.sounding.contains_key(&(key as u8)).then(|| self.release(key as u8))
+            }
+            _ => None,
+        }
+    }
+
+    fn release(&mut self, key: u8) -> Vec<PianoEvent> {
+        self.sounding
+            .remove(&key)
+            .unwrap_or_default()
+            .into_iter()
+            .map(PianoEvent::KeyRelease)
+            .collect()
+    }
+}
+
+/// Where chord sets are saved as presets, one JSON file each.
+pub struct ChordStore {
+    dir: Option<PathBuf>,
+}
+
+impl ChordStore {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+
+    /// Names of the saved chord sets.
+    pub fn names(&self) -> Vec<String> {
+        let entries = match self.dir.as_deref().map(fs::read_dir) {
+            Some(Ok(entries)) => entries,
+            _ => {
+                return Vec::new();
+            }
+        };
+
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter_map(|path| read(&path).ok())
+            .map(|chord_set| chord_set.name)
+            .collect();
+        names.sort();
+
+        names
+    }
+
+    pub fn save(&self, chord_set: &ChordSet) -> Result<(), PianoError> {
+        let dir = self.dir.as_deref().ok_or_else(|| PianoError::Chord("nowhere to save to".to_string()))?;
+        let json = serde_json::to_string_pretty(chord_set).map_err(|e| PianoError::Chord(e.to_string()))?;
+
+        fs::create_dir_all(dir)
+            .and_then(|_| fs::write(dir.join(file_name(&chord_set.name)), json))
+            .map_err(|e| PianoError::Chord(e.to_string()))
+    }
+
+    pub fn load(&self, name: &str) -> Result<ChordSet, PianoError> {
+        match &self.dir {
+            Some(dir) => read(&dir.join(file_name(name))),
+            None => Err(PianoError::Chord(format!("no chord set named '{}'", name))),
+        }
+    }
+}
+
+fn file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    format!("{}.json", name)
+}
+
+fn read(path: &Path) -> Result<ChordSet, PianoError> {
+    let json = fs::read_to_string(path).map_err(|e| PianoError::Chord(e.to_string()))?;
+    serde_json::from_str(&json).map_err(|e| PianoError::Chord(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::piano_listen::{ Percent, Velocity };
+    use crate::velocity::VelocityCurve;
+
+    fn press(note: u8) -> PianoEvent {
+        let velocity = Velocity::new(100);
+        let percent = Percent::with_curve(velocity, &VelocityCurve::default());
+        PianoEvent::KeyPress(PianoKeyCode::try_from(note).unwrap(), percent, velocity)
+    }
+
+    fn release(note: u8) -> PianoEvent {
+        PianoEvent::KeyRelease(PianoKeyCode::try_from(note).unwrap())
+    }
+
+    // The notes started (positive) and stopped (negative) by `piano_events`
+    fn notes(piano_events: &[PianoEvent]) -> Vec<i16> {
+        piano_events
+            .iter()
+            .filter_map(|piano_event| match piano_event {
+                PianoEvent::KeyPress(key, _, _) => Some(*key as i16),
+                PianoEvent::KeyRelease(key) => Some(-(*key as i16)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // A chord memory holding the C major triad 60, 64, 67 as "Major"
+    fn memory_with_major() -> (ChordMemory, ChordPlayer) {
+        let memory = ChordMemory::default();
+        let mut player = ChordPlayer::new(memory.clone());
+        for note in [64, 60, 67] {
+            assert!(player.play(&press(note)).is_none());
+        }
+        memory.capture("Major".to_string(), Vec::new()).unwrap();
+        for note in [60, 64, 67] {
+            assert!(player.play(&release(note)).is_none());
+        }
+        (memory, player)
+    }
+
+    #[test]
+    fn captures_the_held_chord_from_its_lowest_note() {
+        let (memory, _) = memory_with_major();
+
+        let chord_set = memory.chord_set();
+        assert_eq!(chord_set.voicings.len(), 1);
+        assert_eq!(chord_set.voicings[0].name, "Major");
+        assert_eq!(chord_set.voicings[0].intervals, vec![0, 4, 7]);
+        assert!(matches!(memory.capture("Empty".to_string(), Vec::new()), Err(PianoError::Chord(_))));
+    }
+
+    #[test]
+    fn plays_the_chord_from_the_key_struck() {
+        let (memory, mut player) = memory_with_major();
+        memory.set_enabled(true);
+
+        let chord = player.play(&press(62)).unwrap();
+
+        assert_eq!(notes(&chord), vec![62, 66, 69]);
+    }
+
+    #[test]
+    fn releasing_the_key_stops_its_whole_chord() {
+        let (memory, mut player) = memory_with_major();
+        memory.set_enabled(true);
+        player.play(&press(57)).unwrap();
+        // Whatever the memory holds by now, the key stops the notes it started
+        memory.set_chord_set(ChordSet::default());
+
+        let released = player.play(&release(57)).unwrap();
+
+        assert_eq!(notes(&released), vec![-57, -61, -64]);
+        assert!(player.play(&release(57)).is_none());
+    }
+}
@@ -0,0 +1,307 @@
+use std::sync::{ Arc, Mutex, PoisonError };
+use std::time::Duration;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::backend::{ Backend, OutputConnection };
+use crate::error::PianoError;
+use crate::metronome::{ Metronome, PULSES_PER_BEAT };
+use crate::piano_listen::StateCode;
+use crate::recording::Recording;
+
+/// Status byte of a MIDI clock pulse.
+pub const CLOCK_PULSE: u8 = StateCode::Clock as u8;
+pub const START: u8 = StateCode::Start as u8;
+pub const CONTINUE: u8 = StateCode::Continue as u8;
+pub const STOP: u8 = StateCode::Stop as u8;
+pub const SONG_POSITION: u8 = StateCode::SongPosition as u8;
+
+// A song position pointer counts sixteenth notes
+const PULSES_PER_SONG_STEP: u32 = PULSES_PER_BEAT / 4;
+
+// Share of a new pulse interval that goes into the tempo estimate, evening out the jitter
+const TEMPO_SMOOTHING: f64 = 0.1;
+
+/// A clock or transport message, or a beat worked out from them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TransportEvent {
+    Pulse,
+    Start,
+    Continue,
+    Stop,
+    /// Song position pointer, in clock pulses from the start of the song
+    Seek(u32),
+    /// A beat has begun, counted from the start of the song. Never sent, only passed on to the UI
+    /// in place of the pulses.
+    Beat(u32),
+}
+
+impl TransportEvent {
+    /// The event `message` stands for, `None` if it is no clock or transport message.
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        match *message {
+            [CLOCK_PULSE] => Some(Self::Pulse),
+            [START] => Some(Self::Start),
+            [CONTINUE] => Some(Self::Continue),
+            [STOP] => Some(Self::Stop),
+            [SONG_POSITION, low, high] => {
+                let steps = (((high & 0x7f) as u32) << 7) | ((low & 0x7f) as u32);
+                Some(Self::Seek(steps * PULSES_PER_SONG_STEP))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Where the song is, going by an incoming MIDI clock.
+///
+/// Pulses only move the song on while it is playing. A clock that never sent a start, stop or
+/// continue is taken to be free-running, and every one of its pulses counts.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Transport {
+    pub playing: bool,
+    /// Clock pulses since the start of the song
+    pub position: u32,
+    /// Tempo worked out from the time between pulses, once two of them came in
+    pub bpm: Option<f64>,
+    #[serde(skip)]
+    has_transport: bool,
+    #[serde(skip)]
+    last_pulse: Option<u64>,
+}
+
+impl Transport {
+    /// Follows `event`, which arrived at `stamp` microseconds. For a pulse that counts, returns its
+    /// position in the song.
+    pub fn follow(&mut self, event: TransportEvent, stamp: u64) -> Option<u32> {
+        match event {
+            TransportEvent::Pulse => {
+                if let Some(last) = self.last_pulse.filter(|last| stamp > *last) {
+                    let bpm = 60_000_000.0 / (((stamp - last) as f64) * (PULSES_PER_BEAT as f64));
+                    self.bpm = Some(match self.bpm {
+                        Some(current) => current + (bpm - current) * TEMPO_SMOOTHING,
+                        None => bpm,
+                    });
+                }
+                self.last_pulse = Some(stamp);
+
+                if !self.playing && self.has_transport {
+                    return None;
+                }
+                let position = self.position;
+                self.position += 1;
+                Some(position)
+            }
+            TransportEvent::Start => {
+                self.has_transport = true;
+                self.playing = true;
+                self.position = 0;
+                None
+            }
+            TransportEvent::Continue => {
+                self.has_transport = true;
+                self.playing = true;
+                None
+            }
+            TransportEvent::Stop => {
+                self.has_transport = true;
+                self.playing = false;
+                None
+            }
+            TransportEvent::Seek(position) => {
+                self.position = position;
+                None
+            }
+            TransportEvent::Beat(_) => None,
+        }
+    }
+}
+
+/// The transport shared by every input of a listener, so clock from any of them drives it.
+#[derive(Debug, Clone, Default)]
+pub struct TransportControl(Arc<Mutex<Transport>>);
+
+impl TransportControl {
+    pub fn follow(&self, event: TransportEvent, stamp: u64) -> Option<u32> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).follow(event, stamp)
+    }
+}
+
+/// Times of the beats of the MIDI clock recorded along with the notes, for lining them up on a
+/// grid. Empty if no clock was recorded.
+pub fn beat_grid(recording: &Recording) -> Vec<Duration> {
+    let mut transport = Transport::default();
+    recording.recording
+        .iter()
+        .filter_map(|(at, _, message)| {
+            let event = TransportEvent::parse(message)?;
+            let position = transport.follow(event, at.as_micros() as u64)?;
+            (position % PULSES_PER_BEAT == 0).then_some(*at)
+        })
+        .collect()
+}
+
+/// Sending MIDI clock on an output port, for other gear to follow.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClockOutput {
+    /// Name of the output port
+    pub output: String,
+    pub bpm: f32,
+}
+
+/// Drives other gear as clock master: a start when it begins, pulses at the set tempo, and a stop
+/// when it ends.
+pub struct ClockMaster {
+    metronome: Metronome,
+    connection: Arc<Mutex<Box<dyn OutputConnection>>>,
+}
+
+impl ClockMaster {
+    pub fn start(backend: &Backend, settings: &ClockOutput) -> Result<Self, PianoError> {
+        if settings.bpm.is_nan() || settings.bpm <= 0.0 {
+            return Err(PianoError::Clock("tempo must be above 0".to_string()));
+        }
+
+        let mut connection = backend.connect_output(&settings.output)?;
+        connection.send(&[START])?;
+
+        let connection = Arc::new(Mutex::new(connection));
+        let pulses = Arc::clone(&connection);
+        let metronome = Metronome::start(settings.bpm, move || {
+            // A pulse that fails is gone, the next one keeps time all the same
+            let _ = pulses.lock().unwrap_or_else(PoisonError::into_inner).send(&[CLOCK_PULSE]);
+        });
+
+        Ok(Self { metronome, connection })
+    }
+
+    pub fn stop(self) {
+        self.metronome.stop();
+        if let Some(connection) = Arc::into_inner(self.connection) {
+            let mut connection = connection.into_inner().unwrap_or_else(PoisonError::into_inner);
+            let _ = connection.send(&[STOP]);
+            connection.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend::MockBackend;
+
+    const SYNTH: &str = "Synth";
+
+    // Time between pulses at 125 bpm, in microseconds
+    const PULSE_AT_125: u64 = 20_000;
+
+    fn follow(transport: &mut Transport, messages: &[&[u8]]) -> Vec<Option<u32>> {
+        messages
+            .iter()
+            .enumerate()
+            .map(|(index, message)| {
+                let event = TransportEvent::parse(message).unwrap();
+                transport.follow(event, (index as u64) * PULSE_AT_125)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tempo_follows_the_pulse_spacing() {
+        let mut transport = Transport::default();
+        assert!(transport.bpm.is_none());
+
+        for index in 0..4 {
+            transport.follow(TransportEvent::Pulse, index * PULSE_AT_125);
+        }
+        assert!((transport.bpm.unwrap() - 125.0).abs() < 1e-9);
+
+        // A faster clock pulls the estimate up gradually
+        let mut stamp = 3 * PULSE_AT_125;
+        for _ in 0..200 {
+            stamp += PULSE_AT_125 / 2;
+            transport.follow(TransportEvent::Pulse, stamp);
+        }
+        assert!((transport.bpm.unwrap() - 250.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn start_stop_and_continue_move_the_song() {
+        let mut transport = Transport::default();
+
+        let positions = follow(&mut transport, &[
+            &[CLOCK_PULSE],
+            &[START],
+            &[CLOCK_PULSE],
+            &[CLOCK_PULSE],
+            &[STOP],
+            &[CLOCK_PULSE],
+            &[CONTINUE],
+            &[CLOCK_PULSE],
+            &[START],
+            &[CLOCK_PULSE],
+        ]);
+
+        // Free-running until the first start, which goes back to the top
+        assert_eq!(positions, vec![
+            Some(0),
+            None,
+            Some(0),
+            Some(1),
+            None,
+            None,
+            None,
+            Some(2),
+            None,
+            Some(0),
+        ]);
+        assert!(transport.playing);
+    }
+
+    #[test]
+    fn song_position_seeks_to_its_pulse() {
+        assert_eq!(TransportEvent::parse(&[SONG_POSITION, 0x10, 0x01]), Some(TransportEvent::Seek(864)));
+
+        let mut transport = Transport::default();
+        let positions = follow(&mut transport, &[
+            &[STOP],
+            &[SONG_POSITION, 0x04, 0x00],
+            &[CONTINUE],
+            &[CLOCK_PULSE],
+        ]);
+
+        assert_eq!(positions, vec![None, None, None, Some(24)]);
+    }
+
+    #[test]
+    fn beats_fall_on_every_24th_pulse() {
+        let mut recording = Recording::new();
+        let source = recording.source("Clock");
+        recording.push((Duration::ZERO, source, vec![START]));
+        for pulse in 0..50 {
+            recording.push((Duration::from_millis(20 * pulse), source, vec![CLOCK_PULSE]));
+        }
+
+        assert_eq!(beat_grid(&recording), vec![
+            Duration::ZERO,
+            Duration::from_millis(480),
+            Duration::from_millis(960),
+        ]);
+    }
+
+    #[test]
+    fn clock_master_starts_pulses_and_stops() {
+        let mock = MockBackend::new(&[], &[SYNTH]);
+        let settings = ClockOutput { output: SYNTH.to_string(), bpm: 300.0 };
+
+        let master = ClockMaster::start(&Backend::new(mock.clone()), &settings).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        master.stop();
+
+        let sent = mock.sent(SYNTH);
+        assert_eq!(sent.first(), Some(&vec![START]));
+        assert_eq!(sent.last(), Some(&vec![STOP]));
+        assert!(sent.len() > 3);
+        assert!(sent[1..sent.len() - 1].iter().all(|message| *message == vec![CLOCK_PULSE]));
+    }
+}
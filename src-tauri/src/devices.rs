@@ -0,0 +1,80 @@
+use std::collections::BTreeSet;
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{ unbounded, Receiver, Sender };
+use serde::Serialize;
+
+use crate::backend::Backend;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum DeviceEvent {
+    Connected(String),
+    Disconnected(String),
+}
+
+/// Polls the available MIDI input ports and tells subscribers when one appears or disappears.
+///
+/// midir has no portable way to be notified of port changes, so the port names are compared
+/// every `interval`.
+pub struct DeviceWatcher {
+    ports: Arc<Mutex<BTreeSet<String>>>,
+    subscribers: Arc<Mutex<Vec<Sender<DeviceEvent>>>>,
+}
+
+impl DeviceWatcher {
+    pub fn spawn(backend: Backend, interval: Duration) -> Self {
+        let ports = Arc::new(Mutex::new(BTreeSet::new()));
+        let subscribers: Arc<Mutex<Vec<Sender<DeviceEvent>>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let watcher = Self { ports: Arc::clone(&ports), subscribers: Arc::clone(&subscribers) };
+
+        thread::spawn(move || {
+            loop {
+                let current: BTreeSet<String> = match backend.input_ports() {
+                    Ok(ports) => ports.into_iter().collect(),
+                    Err(e) => {
+                        println!("Device watcher failed to list ports: {}", e);
+                        thread::sleep(interval);
+                        continue;
+                    }
+                };
+
+                let events: Vec<DeviceEvent> = {
+                    let mut ports = ports.lock().unwrap();
+                    let events = ports
+                        .difference(&current)
+                        .map(|name| DeviceEvent::Disconnected(name.clone()))
+                        .chain(
+                            current.difference(&ports).map(|name| DeviceEvent::Connected(name.clone()))
+                        )
+                        .collect();
+                    *ports = current;
+                    events
+                };
+
+                if !events.is_empty() {
+                    // Subscribers that went away are dropped along the way
+                    subscribers.lock().unwrap().retain(|subscriber| {
+                        events.iter().all(|event| subscriber.send(event.clone()).is_ok())
+                    });
+                }
+
+                thread::sleep(interval);
+            }
+        });
+
+        watcher
+    }
+
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn ports(&self) -> Vec<String> {
+        self.ports.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,121 @@
+use std::error::Error;
+use std::fmt;
+
+use midir::{ ConnectError, InitError, PortInfoError, SendError };
+use serde::ser::{ Serialize, SerializeStruct, Serializer };
+
+/// Everything that can go wrong in the MIDI, recording and playback layers.
+///
+/// Reaches the frontend either as the rejection of a command or as a `pianoerror` event, in both
+/// cases serialized as `{ kind, message }`.
+#[derive(Debug, Clone)]
+pub enum PianoError {
+    // MIDI
+    NoInputPort,
+    NoOutputPort,
+    InvalidPort(String),
+    Midi(String),
+    UnhandledMessage(String),
+    Profile(String),
+    Calibration(String),
+    InvalidZone(String),
+    Arpeggiator(String),
+    Chord(String),
+    Clock(String),
+
+    // Recording
+    RecordingNotFound(String),
+    NothingCaptured,
+    InvalidPunchRange,
+    Journal(String),
+    Timecode(String),
+
+    // Sessions
+    SessionNotFound(u32),
+    SessionPanicked,
+}
+
+impl PianoError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::NoInputPort => "NoInputPort",
+            Self::NoOutputPort => "NoOutputPort",
+            Self::InvalidPort(_) => "InvalidPort",
+            Self::Midi(_) => "Midi",
+            Self::UnhandledMessage(_) => "UnhandledMessage",
+            Self::Profile(_) => "Profile",
+            Self::Calibration(_) => "Calibration",
+            Self::InvalidZone(_) => "InvalidZone",
+            Self::Arpeggiator(_) => "Arpeggiator",
+            Self::Chord(_) => "Chord",
+            Self::Clock(_) => "Clock",
+            Self::RecordingNotFound(_) => "RecordingNotFound",
+            Self::NothingCaptured => "NothingCaptured",
+            Self::InvalidPunchRange => "InvalidPunchRange",
+            Self::Journal(_) => "Journal",
+            Self::Timecode(_) => "Timecode",
+            Self::SessionNotFound(_) => "SessionNotFound",
+            Self::SessionPanicked => "SessionPanicked",
+        }
+    }
+}
+
+impl fmt::Display for PianoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoInputPort => write!(f, "no input port found"),
+            Self::NoOutputPort => write!(f, "no output port found"),
+            Self::InvalidPort(e) => write!(f, "invalid port selected: {}", e),
+            Self::Midi(e) => write!(f, "MIDI error: {}", e),
+            Self::UnhandledMessage(e) => write!(f, "unhandled message: {}", e),
+            Self::Profile(e) => write!(f, "invalid device profile: {}", e),
+            Self::Calibration(e) => write!(f, "calibration failed: {}", e),
+            Self::InvalidZone(e) => write!(f, "invalid zone: {}", e),
+            Self::Arpeggiator(e) => write!(f, "invalid arpeggiator settings: {}", e),
+            Self::Chord(e) => write!(f, "chord memory error: {}", e),
+            Self::Clock(e) => write!(f, "MIDI clock error: {}", e),
+            Self::RecordingNotFound(name) => write!(f, "no recording named '{}'", name),
+            Self::NothingCaptured => write!(f, "nothing has been captured yet"),
+            Self::InvalidPunchRange => write!(f, "punch-out must come after punch-in"),
+            Self::Journal(e) => write!(f, "journal error: {}", e),
+            Self::Timecode(e) => write!(f, "invalid timecode: {}", e),
+            Self::SessionNotFound(id) => write!(f, "no session with id {}", id),
+            Self::SessionPanicked => write!(f, "session thread panicked"),
+        }
+    }
+}
+
+impl Error for PianoError {}
+
+impl Serialize for PianoError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("PianoError", 2)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+impl From<InitError> for PianoError {
+    fn from(e: InitError) -> Self {
+        Self::Midi(e.to_string())
+    }
+}
+
+impl From<PortInfoError> for PianoError {
+    fn from(e: PortInfoError) -> Self {
+        Self::Midi(e.to_string())
+    }
+}
+
+impl<T> From<ConnectError<T>> for PianoError {
+    fn from(e: ConnectError<T>) -> Self {
+        Self::Midi(e.to_string())
+    }
+}
+
+impl From<SendError> for PianoError {
+    fn from(e: SendError) -> Self {
+        Self::Midi(e.to_string())
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use crate::recording::{ Recording, SourceId };
+
+const EXTENSION: &str = "journal";
+
+// Marks the lines naming a source rather than holding a chunk
+const SOURCE_PREFIX: &str = "source ";
+
+/// Append-only file mirroring a take while it is being recorded.
+///
+/// Every chunk is written as its own line, `<µs since start> <source> <byte> <byte> ...`, as soon
+/// as it arrives, so whatever made it to disk can be turned back into a `Recording` after a crash.
+/// The sources are named up front, one `source <name>` line each in the order of their ids.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let path = dir.join(format!("take-{}.{}", started.as_millis(), EXTENSION));
+        let file = OpenOptions::new().create_new(true).append(true).open(&path)?;
+
+        Ok(Self { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Names the next source, which must happen before any of its chunks are appended.
+    pub fn add_source(&mut self, name: &str) -> io::Result<()> {
+        // A name spanning lines would be taken for chunks
+        let name = name.replace('\n', " ");
+        self.file.write_all(format!("{}{}\n", SOURCE_PREFIX, name).as_bytes())
+    }
+
+    pub fn append(&mut self, chunk: &(Duration, SourceId, Vec<u8>)) -> io::Result<()> {
+        let (time, source, message) = chunk;
+        let mut line = format!("{} {}", time.as_micros(), source);
+        for byte in message {
+            line.push(' ');
+            line.push_str(&byte.to_string());
+        }
+        line.push('\n');
+
+        // A single write per line, so a crash can at most leave the last line torn
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+/// Reads a journal back into a `Recording`, skipping a torn or otherwise unreadable line.
+pub fn read(path: &Path) -> io::Result<Recording> {
+    let contents = fs::read_to_string(path)?;
+    let mut recording = Recording::new();
+
+    for line in contents.split_inclusive('\n') {
+        let line = match line.strip_suffix('\n') {
+            Some(line) => line,
+            None => {
+                continue;
+            }
+        };
+        if let Some(name) = line.strip_prefix(SOURCE_PREFIX) {
+            recording.sources.push(name.to_string());
+        } else if let Some(chunk) = parse_line(line) {
+            recording.push(chunk);
+        }
+    }
+
+    Ok(recording)
+}
+
+/// Removes the journal of a take that was ended normally, or whose recovered copy was saved or
+/// thrown away.
+pub fn discard(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        println!("Failed to remove journal {:?}: {}", path, e);
+    }
+}
+
+/// Turns every unfinished journal in `dir` into a named recording, along with the path of its
+/// journal. The journals are left in place, as they are the only copy of the take until it is
+/// saved or discarded. Journals without a single chunk are removed right away.
+pub fn recover(dir: &Path) -> Vec<(String, Recording, PathBuf)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => {
+            return Vec::new();
+        }
+    };
+
+    let mut recovered = Vec::new();
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.extension().and_then(|extension| extension.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+
+        match read(&path) {
+            Ok(recording) if recording.recording.is_empty() => discard(&path),
+            Ok(recording) => {
+                let name = format!("Recovered {}", path.file_stem().unwrap_or_default().to_string_lossy());
+                recovered.push((name, recording, path));
+            }
+            Err(e) => { println!("Failed to recover journal {:?}: {}", path, e) }
+        }
+    }
+
+    recovered
+}
+
+fn parse_line(line: &str) -> Option<(Duration, SourceId, Vec<u8>)> {
+    let mut parts = line.split_whitespace();
+    let time = Duration::from_micros(parts.next()?.parse().ok()?);
+    let source = parts.next()?.parse().ok()?;
+    let message = parts.map(|byte| byte.parse().ok()).collect::<Option<Vec<u8>>>()?;
+
+    if message.is_empty() {
+        return None;
+    }
+
+    Some((time, source, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of its own for every test, as they run in parallel
+    fn scratch_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("virtual-piano-journal-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn recover_keeps_the_journal_until_the_take_is_dealt_with() {
+        let dir = scratch_dir("recover");
+        let mut journal = Journal::create(&dir).unwrap();
+        journal.add_source("Piano").unwrap();
+        journal.append(&(Duration::from_millis(5), 0, vec![0x90, 60, 100])).unwrap();
+
+        let recovered = recover(&dir);
+        assert_eq!(recovered.len(), 1);
+        let (_, recording, path) = &recovered[0];
+        assert_eq!(recording.recording, vec![(Duration::from_millis(5), 0, vec![0x90, 60, 100])]);
+        assert_eq!(path, journal.path());
+        assert_eq!(recover(&dir).len(), 1);
+
+        discard(path);
+        assert!(recover(&dir).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_skips_torn_and_unreadable_lines() {
+        let dir = scratch_dir("torn");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("take.journal");
+        let lines = [
+            "source Piano\n",
+            "1000 0 144 60 100\n",
+            "2000 0 144 sixty 100\n",
+            "2500 0\n",
+            "3000 0 128 60 0\n",
+            // A crash in the middle of a write
+            "4000 0 14",
+        ];
+        fs::write(&path, lines.concat()).unwrap();
+
+        let recording = read(&path).unwrap();
+
+        assert_eq!(recording.sources, ["Piano"]);
+        assert_eq!(
+            recording.recording,
+            vec![
+                (Duration::from_millis(1), 0, vec![0x90, 60, 100]),
+                (Duration::from_millis(3), 0, vec![0x80, 60, 0])
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_gives_back_what_was_appended() {
+        let dir = scratch_dir("round-trip");
+        let chunks = vec![
+            (Duration::from_micros(1_250), 0, vec![0x90, 60, 100]),
+            (Duration::from_micros(1_250), 1, vec![0xb0, 64, 127]),
+            (Duration::from_secs(3_600), 0, vec![0xf8]),
+        ];
+        let mut journal = Journal::create(&dir).unwrap();
+        journal.add_source("Piano").unwrap();
+        journal.add_source("Pedals\nand more").unwrap();
+        for chunk in &chunks {
+            journal.append(chunk).unwrap();
+        }
+
+        let recording = read(journal.path()).unwrap();
+
+        assert_eq!(recording.sources, ["Piano", "Pedals and more"]);
+        assert_eq!(recording.recording, chunks);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn recover_removes_journals_without_chunks() {
+        let dir = scratch_dir("empty");
+        let mut journal = Journal::create(&dir).unwrap();
+        journal.add_source("Piano").unwrap();
+
+        assert!(recover(&dir).is_empty());
+        assert!(!journal.path().exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,163 +1,454 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::collections::HashMap;
-use std::sync::{ Arc, Mutex };
+use std::path::PathBuf;
 use std::thread;
-use std::time::{ Duration, Instant };
-use crossbeam_channel::{ bounded, Receiver, Sender };
-use lazy_static::lazy_static;
-
-use piano_listen::{ listen, play, Alpha, PianoEvent, PianoKeyCode, StateCode };
-use tauri::Manager;
-
+use std::time::Duration;
+use crossbeam_channel::bounded;
+use serde::Deserialize;
+
+use arpeggiator::ArpSettings;
+use backend::{ Backend, MidirBackend };
+use chords::{ ChordMemory, ChordSet, ChordStore, Voicing };
+use clock::{ beat_grid, ClockOutput };
+use devices::{ DeviceEvent, DeviceWatcher };
+use error::PianoError;
+use journal::Journal;
+use pedal::{ note_spans, NoteSpan };
+use velocity::{ median, VelocityCurve };
+use piano_listen::{ listen, play, punch_record, sample_velocities, ListenOptions, PianoEvent, PlayOptions };
+use profile::{ DeviceProfile, ProfileStore };
+use routing::Route;
+use scale::{ ScaleControl, ScaleLock };
+use session::{ SessionId, SessionInfo, SessionKind, SessionManager, SessionOutcome };
+use timecode::TimecodeSync;
+use transpose::{ Transpose, TransposeControl };
+use tauri::{ Manager, State };
+use zones::Zone;
+
+pub mod arpeggiator;
+pub mod backend;
+pub mod capture;
+pub mod chords;
+pub mod clock;
+pub mod devices;
+pub mod error;
+pub mod journal;
+pub mod metronome;
+#[cfg(test)]
+pub mod mock_backend;
+pub mod pedal;
 pub mod piano_listen;
+pub mod profile;
+pub mod recording;
+pub mod routing;
+pub mod scale;
+pub mod scheduler;
+pub mod session;
+pub mod timecode;
+pub mod transpose;
+pub mod velocity;
+pub mod virtual_ports;
+pub mod zones;
+
+// How far back `save_captured_recording` can reach
+const CAPTURE_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+// How often the available MIDI ports are checked for changes
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Key presses collected per level when calibrating velocity, and how long to wait for them
+const CALIBRATION_PRESSES: usize = 8;
+const CALIBRATION_TIMEOUT: Duration = Duration::from_secs(20);
+
+fn journal_dir(app: &tauri::AppHandle) -> Option<PathBuf> {
+    app.path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join("journal"))
+}
 
-lazy_static! {
-    static ref LISTENER_STATE: Mutex<Option<ListenerState>> = Mutex::new(None);
-    
-    #[derive(Debug)]
-    static ref RECORDINGS: Mutex<HashMap<String, Recording>> = Mutex::new(HashMap::new());
+fn profile_dir(app: &tauri::AppHandle) -> Option<PathBuf> {
+    app.path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join("profiles"))
 }
 
-#[derive(Debug, Clone)]
-pub struct Recording {
-    pub recording: Vec<(Duration, Vec<u8>)>,
+fn chord_dir(app: &tauri::AppHandle) -> Option<PathBuf> {
+    app.path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join("chords"))
 }
 
-impl Recording {
-    pub fn new() -> Self {
-        Self { recording: Vec::new() }
+fn emit_piano_events(
+    app: tauri::AppHandle
+) -> impl Fn(&str, Result<PianoEvent, PianoError>) + Send + Sync + 'static {
+    let scale = app.state::<ScaleControl>().inner().clone();
+    move |source: &str, piano_event: Result<PianoEvent, PianoError>| {
+        match piano_event {
+            Ok(piano_event) => {
+                let out_of_scale = scale.get().flags(&piano_event);
+                let event = piano_event.to_client_event(source).flagged(out_of_scale);
+                if let Err(e) = app.emit("pianoevent", event) {
+                    println!("Failed to emit event: {}", e);
+                }
+            }
+            // Messages we don't handle (active sensing, pitch bends, ...) are routine, dropped silently
+            Err(PianoError::UnhandledMessage(_)) => {}
+            // Failures while running, like losing the journal or failing to reconnect
+            Err(e) => emit_error(&app, &e),
+        }
     }
+}
 
-    pub fn from(recording: Vec<(Duration, Vec<u8>)>) -> Self {
-        Self { recording }
+fn emit_error(app: &tauri::AppHandle, error: &PianoError) {
+    println!("Error: {}", error);
+    if let Err(e) = app.emit("pianoerror", error) {
+        println!("Failed to emit error: {}", e);
     }
+}
 
-    pub fn push(&mut self, chunk: (Duration, Vec<u8>)) {
-        self.recording.push(chunk)
-    }
+/// Runs a session on its own thread, reporting a failure to the frontend as soon as it happens
+/// rather than when the session is stopped.
+fn spawn_session<F>(app: tauri::AppHandle, session: F) -> thread::JoinHandle<SessionOutcome>
+    where F: FnOnce() -> SessionOutcome + Send + 'static
+{
+    thread::spawn(move || {
+        let outcome = session();
+        if let Err(e) = &outcome {
+            emit_error(&app, e);
+        }
+        outcome
+    })
 }
 
-struct ListenerState {
-    handle: thread::JoinHandle<Option<Recording>>,
-    stop_sender: Sender<()>,
+#[tauri::command]
+fn play_recording(
+    app: tauri::AppHandle,
+    sessions: State<'_, SessionManager>,
+    backend: State<'_, Backend>,
+    name: String,
+    transpose: Option<Transpose>,
+    timecode: Option<TimecodeSync>,
+    output: Option<String>
+) -> Result<SessionId, PianoError> {
+    let recording = sessions.recording(&name)?.transposed(transpose.unwrap_or_default());
+    let (stop_sender, stop_receiver) = bounded(1);
+    let options = PlayOptions { output, timecode, backend: backend.inner().clone() };
+
+    let handle = spawn_session(app, move || play(recording, options, stop_receiver).map(|_| None));
+
+    Ok(sessions.insert(SessionKind::Play, handle, stop_sender, None))
 }
 
 #[tauri::command]
-fn play_recording() -> bool {
-    let recording = { RECORDINGS.lock().unwrap().get("First recording").unwrap().clone() };
-    let handle = thread::spawn(move || {
-        play(recording).unwrap();
+fn punch_in_recording(
+    app: tauri::AppHandle,
+    sessions: State<'_, SessionManager>,
+    profiles: State<'_, ProfileStore>,
+    backend: State<'_, Backend>,
+    name: String,
+    punch_in_ms: u64,
+    punch_out_ms: u64
+) -> Result<SessionId, PianoError> {
+    let recording = sessions.recording(&name)?;
+    let handler = emit_piano_events(app.clone());
+    let profiles = profiles.profiles();
+    let backend = backend.inner().clone();
+    let (stop_sender, stop_receiver) = bounded(1);
+
+    let session_app = app.clone();
+    let handle = spawn_session(app, move || {
+        let punched = punch_record(
+            &backend,
+            &recording,
+            Duration::from_millis(punch_in_ms),
+            Duration::from_millis(punch_out_ms),
+            &profiles,
+            handler,
+            stop_receiver
+        )?;
+        // Replaced as soon as the take is in, whether or not the session is ever stopped
+        if let Some(punched) = punched {
+            session_app.state::<SessionManager>().insert_recording(name, punched);
+        }
+        Ok(None)
     });
-    handle.join().unwrap();
-    true
+
+    Ok(sessions.insert(SessionKind::Punch, handle, stop_sender, None))
 }
 
 #[tauri::command]
-fn is_listening() -> bool {
-    let listener_state = LISTENER_STATE.lock().expect("Error when locking");
-    listener_state.is_some()
+fn recording_notes(
+    sessions: State<'_, SessionManager>,
+    profiles: State<'_, ProfileStore>,
+    name: String,
+    transpose: Option<Transpose>
+) -> Result<Vec<NoteSpan>, PianoError> {
+    let recording = sessions.recording(&name)?.transposed(transpose.unwrap_or_default());
+    Ok(note_spans(&recording, &profiles.profiles()))
 }
 
+/// Times of the beats of the MIDI clock the recording was made to, for its grid.
 #[tauri::command]
-fn end_piano_recording(name: String) -> bool {
-    let mut listener_state = LISTENER_STATE.lock().expect("Error when locking");
-    if let Some(listener_state) = listener_state.take() {
-        let _ = listener_state.stop_sender.send(());
-        let recording = listener_state.handle.join().unwrap().unwrap();
-        RECORDINGS.lock().unwrap().insert(name, recording);
-    }
+fn recording_grid(sessions: State<'_, SessionManager>, name: String) -> Result<Vec<Duration>, PianoError> {
+    Ok(beat_grid(&sessions.recording(&name)?))
+}
 
-    true
+#[tauri::command]
+fn get_transpose(transpose: State<'_, TransposeControl>) -> Transpose {
+    transpose.get()
 }
 
+/// Changes the transpose of every listener, notes already held are released where they were
+/// pressed.
 #[tauri::command]
-fn spawn_piano_recorder(app: tauri::AppHandle) -> bool {
-    let app = Arc::new(Mutex::new(app));
-    kill_piano_listener();
+fn set_transpose(transpose: State<'_, TransposeControl>, semitones: i8, octaves: i8) -> Transpose {
+    let shifted = Transpose { semitones, octaves };
+    transpose.set(shifted);
+    shifted
+}
 
-    let handler = {
-        let app = Arc::clone(&app);
-        move |piano_event: Result<PianoEvent, String>| {
-            match piano_event {
-                Ok(piano_event) => {
-                    let event = piano_event.to_client_event();
-                    let app = app.lock().expect("Failed to lock AppHandle");
-                    app.emit("pianoevent", event).expect("Failed to emit event");
-                }
-                Err(e) => { println!("Error: {}", e) }
-            }
-        }
-    };
+#[tauri::command]
+fn save_captured_recording(sessions: State<'_, SessionManager>, name: String) -> Result<(), PianoError> {
+    sessions.save_capture(name)
+}
 
-    let (stop_sender, stop_receiver) = bounded(1);
+/// Names of the takes recovered after a crash that still need to be saved or discarded.
+#[tauri::command]
+fn list_recovered_recordings(sessions: State<'_, SessionManager>) -> Vec<String> {
+    sessions.recovered()
+}
 
-    let handle = thread::spawn(move || {
-        let recording = listen(handler, true, stop_receiver).expect("Error when listening");
-        Some(Recording::from(recording.unwrap().lock().unwrap().recording.clone()))
-    });
+#[tauri::command]
+fn save_recovered_recording(
+    sessions: State<'_, SessionManager>,
+    name: String,
+    new_name: String
+) -> Result<(), PianoError> {
+    sessions.save_recovered(&name, new_name)
+}
 
-    let mut listener_state = LISTENER_STATE.lock().expect("Error when locking");
-    *listener_state = Some(ListenerState { handle, stop_sender });
+#[tauri::command]
+fn discard_recovered_recording(sessions: State<'_, SessionManager>, name: String) -> Result<(), PianoError> {
+    sessions.discard_recovered(&name)
+}
 
-    true
+#[tauri::command]
+fn is_listening(sessions: State<'_, SessionManager>) -> bool {
+    sessions.is_running(SessionKind::Listen)
 }
 
 #[tauri::command]
-fn spawn_piano_listener(app: tauri::AppHandle) -> bool {
-    {
-        let listener_state = LISTENER_STATE.lock().expect("Error when locking");
-        if listener_state.is_some() {
-            println!("Already listening!");
-            return false;
-        }
-    }
+fn list_sessions(sessions: State<'_, SessionManager>) -> Vec<SessionInfo> {
+    sessions.sessions()
+}
 
-    let app = Arc::new(Mutex::new(app));
+#[tauri::command]
+fn list_input_ports(devices: State<'_, DeviceWatcher>) -> Vec<String> {
+    devices.ports()
+}
 
-    let handler = {
-        let app = Arc::clone(&app);
-        move |piano_event: Result<PianoEvent, String>| {
-            match piano_event {
-                Ok(piano_event) => {
-                    let event = piano_event.to_client_event();
-                    let app = app.lock().expect("Failed to lock AppHandle");
-                    app.emit("pianoevent", event).expect("Failed to emit event");
-                }
-                Err(e) => { println!("Error: {}", e) }
-            }
-        }
-    };
-    let (stop_sender, stop_receiver) = bounded(1);
+#[tauri::command]
+fn list_output_ports(backend: State<'_, Backend>) -> Result<Vec<String>, PianoError> {
+    backend.output_ports()
+}
 
-    let handle = thread::spawn(move || {
-        listen(handler, false, stop_receiver).expect("Error when listening");
+#[tauri::command]
+fn list_profiles(profiles: State<'_, ProfileStore>) -> Vec<DeviceProfile> {
+    profiles.profiles().profiles().to_vec()
+}
 
-        None
-    });
+/// Waits for the player to play a few keys at one level (soft, medium or loud) on `port` and
+/// returns the typical velocity, for `save_velocity_calibration`.
+#[tauri::command]
+async fn calibrate_velocity(backend: State<'_, Backend>, port: String) -> Result<u8, PianoError> {
+    // Waiting for the key presses blocks for up to CALIBRATION_TIMEOUT, keep it off the async runtime
+    let backend = backend.inner().clone();
+    let velocities = tauri::async_runtime::spawn_blocking(move || {
+        sample_velocities(&backend, &port, CALIBRATION_PRESSES, CALIBRATION_TIMEOUT)
+    }).await
+        .map_err(|e| PianoError::Calibration(e.to_string()))??;
+    median(&velocities).ok_or_else(|| PianoError::Calibration("no keys were played".to_string()))
+}
 
-    let mut listener_state = LISTENER_STATE.lock().expect("Error when locking");
-    *listener_state = Some(ListenerState { handle, stop_sender });
+#[tauri::command]
+fn save_velocity_calibration(
+    profiles: State<'_, ProfileStore>,
+    port: String,
+    soft: u8,
+    medium: u8,
+    loud: u8
+) -> Result<DeviceProfile, PianoError> {
+    let curve = VelocityCurve::calibrated(soft, medium, loud)?;
+    profiles.customize(&port, |profile| {
+        profile.velocity_curve = curve;
+    })
+}
+
+#[tauri::command]
+fn set_velocity_curve(
+    profiles: State<'_, ProfileStore>,
+    port: String,
+    curve: VelocityCurve
+) -> Result<DeviceProfile, PianoError> {
+    profiles.customize(&port, |profile| {
+        profile.velocity_curve = curve;
+    })
+}
+
+#[tauri::command]
+fn get_scale_lock(scale: State<'_, ScaleControl>) -> ScaleLock {
+    scale.get()
+}
 
-    true
+#[tauri::command]
+fn set_scale_lock(scale: State<'_, ScaleControl>, scale_lock: ScaleLock) {
+    scale.set(scale_lock);
 }
 
 #[tauri::command]
-fn kill_piano_listener() -> bool {
-    println!("Began killing connection");
+fn set_chord_memory(chords: State<'_, ChordMemory>, enabled: bool) {
+    chords.set_enabled(enabled);
+}
 
-    let mut listener_state = LISTENER_STATE.lock().expect("Error when locking");
-    if let Some(listener_state) = listener_state.take() {
-        let _ = listener_state.stop_sender.send(()).unwrap();
-        drop(listener_state.stop_sender);
-        let _ = listener_state.handle.join();
+/// Memorizes the chord being held, to be played by the keys with the pitch classes in `keys` (every
+/// key if there are none).
+#[tauri::command]
+fn capture_chord(
+    chords: State<'_, ChordMemory>,
+    name: String,
+    keys: Option<Vec<u8>>
+) -> Result<Voicing, PianoError> {
+    chords.capture(name, keys.unwrap_or_default())
+}
+
+#[tauri::command]
+fn current_chord_set(chords: State<'_, ChordMemory>) -> ChordSet {
+    chords.chord_set()
+}
+
+#[tauri::command]
+fn list_chord_sets(store: State<'_, ChordStore>) -> Vec<String> {
+    store.names()
+}
+
+/// Saves the chords in memory as the preset `name`.
+#[tauri::command]
+fn save_chord_set(
+    chords: State<'_, ChordMemory>,
+    store: State<'_, ChordStore>,
+    name: String
+) -> Result<(), PianoError> {
+    let chord_set = ChordSet { name, ..chords.chord_set() };
+    store.save(&chord_set)?;
+    chords.set_chord_set(chord_set);
+    Ok(())
+}
+
+#[tauri::command]
+fn load_chord_set(
+    chords: State<'_, ChordMemory>,
+    store: State<'_, ChordStore>,
+    name: String
+) -> Result<ChordSet, PianoError> {
+    let chord_set = store.load(&name)?;
+    chords.set_chord_set(chord_set.clone());
+    Ok(chord_set)
+}
+
+#[tauri::command]
+fn end_piano_recording(
+    sessions: State<'_, SessionManager>,
+    id: SessionId,
+    name: String
+) -> Result<(), PianoError> {
+    sessions.end_recording(id, name)
+}
+
+/// What the frontend picks for a listener or recorder, everything else is shared by all of them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ListenSetup {
+    ports: Vec<String>,
+    routes: Vec<Route>,
+    zones: Vec<Zone>,
+    arpeggiator: Option<ArpSettings>,
+    clock_output: Option<ClockOutput>,
+}
+
+// The options for a listener set up as `setup`, along with the transpose, scale lock, chords,
+// profiles, ports and backend of the app
+fn listen_options(app: &tauri::AppHandle, setup: ListenSetup) -> ListenOptions {
+    ListenOptions {
+        ports: setup.ports,
+        profiles: app.state::<ProfileStore>().profiles(),
+        routes: setup.routes,
+        devices: Some(app.state::<DeviceWatcher>().subscribe()),
+        transpose: app.state::<TransposeControl>().inner().clone(),
+        scale: app.state::<ScaleControl>().inner().clone(),
+        zones: setup.zones,
+        arpeggiator: setup.arpeggiator,
+        chords: app.state::<ChordMemory>().inner().clone(),
+        clock_output: setup.clock_output,
+        backend: app.state::<Backend>().inner().clone(),
+        ..Default::default()
     }
+}
+
+#[tauri::command]
+fn spawn_piano_recorder(
+    app: tauri::AppHandle,
+    sessions: State<'_, SessionManager>,
+    setup: Option<ListenSetup>
+) -> Result<SessionId, PianoError> {
+    let (stop_sender, stop_receiver) = bounded(1);
+
+    // Recording without a journal still works, it just isn't crash-safe
+    let journal = match journal_dir(&app).map(|dir| Journal::create(&dir)) {
+        Some(Ok(journal)) => Some(journal),
+        Some(Err(e)) => {
+            emit_error(&app, &PianoError::Journal(e.to_string()));
+            None
+        }
+        None => None,
+    };
+    let journal_path = journal.as_ref().map(|journal| journal.path().to_path_buf());
+    let handler = emit_piano_events(app.clone());
+    let options = ListenOptions {
+        record: true,
+        journal,
+        ..listen_options(&app, setup.unwrap_or_default())
+    };
+
+    let handle = spawn_session(app, move || listen(handler, options, stop_receiver));
+
+    Ok(sessions.insert(SessionKind::Record, handle, stop_sender, journal_path))
+}
 
-    // Why does this never print
-    println!("Killed connection");
+#[tauri::command]
+fn spawn_piano_listener(
+    app: tauri::AppHandle,
+    sessions: State<'_, SessionManager>,
+    setup: Option<ListenSetup>
+) -> Result<SessionId, PianoError> {
+    let (stop_sender, stop_receiver) = bounded(1);
+    let handler = emit_piano_events(app.clone());
+    let options = ListenOptions {
+        capture: Some(sessions.capture()),
+        ..listen_options(&app, setup.unwrap_or_default())
+    };
 
-    true
+    let handle = spawn_session(app, move || listen(handler, options, stop_receiver).map(|_| None));
+
+    Ok(sessions.insert(SessionKind::Listen, handle, stop_sender, None))
+}
+
+#[tauri::command]
+fn stop_session(sessions: State<'_, SessionManager>, id: SessionId) -> Result<(), PianoError> {
+    sessions.stop(id).map(|_| ())
 }
 
 // Context menus:
@@ -167,14 +458,75 @@ fn main() {
     tauri::Builder
         ::default()
         .plugin(tauri_plugin_shell::init())
+        .manage(SessionManager::new(CAPTURE_WINDOW))
+        .manage(TransposeControl::default())
+        .manage(ScaleControl::default())
+        .manage(ChordMemory::default())
+        // The virtual ports are there from the start, for other software to connect to
+        .manage(Backend::new(MidirBackend::with_virtual_ports()))
+        .setup(|app| {
+            // Takes that were still being recorded when the app last went down
+            if let Some(dir) = journal_dir(app.handle()) {
+                let sessions = app.state::<SessionManager>();
+                for (name, recording, journal) in journal::recover(&dir) {
+                    println!("Recovered unfinished take as '{}'", name);
+                    sessions.insert_recovered(name, recording, journal);
+                }
+            }
+
+            app.manage(ProfileStore::load(profile_dir(app.handle())));
+            app.manage(ChordStore::new(chord_dir(app.handle())));
+
+            let devices = DeviceWatcher::spawn(app.state::<Backend>().inner().clone(), DEVICE_POLL_INTERVAL);
+            let device_events = devices.subscribe();
+            let handle = app.handle().clone();
+            thread::spawn(move || {
+                for event in device_events {
+                    let emitted = match event {
+                        DeviceEvent::Connected(name) => handle.emit("deviceconnected", name),
+                        DeviceEvent::Disconnected(name) => handle.emit("devicedisconnected", name),
+                    };
+                    if let Err(e) = emitted {
+                        println!("Failed to emit device event: {}", e);
+                    }
+                }
+            });
+            app.manage(devices);
+
+            Ok(())
+        })
         .invoke_handler(
             tauri::generate_handler![
                 spawn_piano_listener,
-                kill_piano_listener,
                 spawn_piano_recorder,
                 end_piano_recording,
+                stop_session,
+                list_sessions,
+                list_input_ports,
+                list_output_ports,
+                list_profiles,
+                calibrate_velocity,
+                save_velocity_calibration,
+                set_velocity_curve,
+                get_transpose,
+                set_transpose,
+                get_scale_lock,
+                set_scale_lock,
+                set_chord_memory,
+                capture_chord,
+                current_chord_set,
+                list_chord_sets,
+                save_chord_set,
+                load_chord_set,
                 is_listening,
-                play_recording
+                play_recording,
+                punch_in_recording,
+                recording_notes,
+                recording_grid,
+                save_captured_recording,
+                list_recovered_recordings,
+                save_recovered_recording,
+                discard_recovered_recording
             ]
         )
 
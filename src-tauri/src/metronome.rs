@@ -0,0 +1,45 @@
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+use crossbeam_channel::{ bounded, Sender };
+
+use crate::scheduler::{ MonotonicClock, Scheduler };
+
+/// Pulses per quarter note, the resolution of MIDI clock.
+pub const PULSES_PER_BEAT: u32 = 24;
+
+// How long before a pulse is due the metronome stops sleeping and spin-waits instead
+const SPIN_BEFORE_PULSE: Duration = Duration::from_micros(300);
+
+/// Internal clock pulsing at `PULSES_PER_BEAT` on its own thread, standing in for an incoming MIDI
+/// clock when nothing sends one.
+pub struct Metronome {
+    stop_sender: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Metronome {
+    /// Calls `pulse` at every pulse of a clock running at `bpm` beats per minute, the first one
+    /// right away.
+    pub fn start<F>(bpm: f32, mut pulse: F) -> Self where F: FnMut() + Send + 'static {
+        let interval = Duration::from_secs_f64(60.0 / ((bpm.max(1.0) as f64) * (PULSES_PER_BEAT as f64)));
+        let (stop_sender, stop_receiver) = bounded(1);
+
+        let handle = thread::spawn(move || {
+            // Pulses are due at absolute times, so the tempo doesn't drift with the scheduling
+            let scheduler = Scheduler::new(MonotonicClock::new(), SPIN_BEFORE_PULSE);
+            let mut count: u32 = 0;
+            while scheduler.wait_until_or_stopped(interval * count, &stop_receiver).is_some() {
+                pulse();
+                count += 1;
+            }
+        });
+
+        Self { stop_sender, handle }
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop_sender.send(());
+        let _ = self.handle.join();
+    }
+}
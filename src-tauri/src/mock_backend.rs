@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError };
+
+use crate::backend::{ InputCallback, InputConnection, MidiBackend, OutputConnection };
+use crate::error::PianoError;
+
+type SharedCallback = Arc<Mutex<InputCallback>>;
+
+#[derive(Default)]
+struct MockState {
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    /// Messages waiting for something to connect to their input
+    scripts: HashMap<String, Vec<(u64, Vec<u8>)>>,
+    /// Open connections by input, each with the id it is closed by
+    connected: HashMap<String, Vec<(u64, SharedCallback)>>,
+    sent: HashMap<String, Vec<Vec<u8>>>,
+    next_id: u64,
+}
+
+/// A backend with nothing but memory behind it, for driving the listener and playback in tests.
+///
+/// Messages scripted for an input are delivered, in order and with their timestamps, as soon as
+/// something connects to it, before the connection is handed back. Messages can also be delivered
+/// to the open connections at any time with `receive`. Everything sent to an output is kept, to be
+/// looked at with `sent`.
+#[derive(Clone, Default)]
+pub struct MockBackend(Arc<Mutex<MockState>>);
+
+impl MockBackend {
+    pub fn new(inputs: &[&str], outputs: &[&str]) -> Self {
+        let state = MockState {
+            inputs: inputs.iter().map(|name| name.to_string()).collect(),
+            outputs: outputs.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        };
+        Self(Arc::new(Mutex::new(state)))
+    }
+
+    /// Queues messages to arrive on `input` once something connects to it, each with the driver
+    /// timestamp (in microseconds) it arrives at.
+    pub fn script(&self, input: &str, messages: Vec<(u64, Vec<u8>)>) {
+        self.lock().scripts.entry(input.to_string()).or_default().extend(messages);
+    }
+
+    /// Delivers `message` to every open connection to `input` right away, returning how many there
+    /// were.
+    pub fn receive(&self, input: &str, stamp: u64, message: &[u8]) -> usize {
+        let callbacks: Vec<SharedCallback> = self.lock().connected
+            .get(input)
+            .map(|connected| connected.iter().map(|(_, callback)| Arc::clone(callback)).collect())
+            .unwrap_or_default();
+        // Called without the state locked, as callbacks send to outputs of their own
+        for callback in &callbacks {
+            (callback.lock().unwrap_or_else(PoisonError::into_inner))(stamp, message);
+        }
+
+        callbacks.len()
+    }
+
+    /// Everything sent to `output` so far, over all its connections.
+    pub fn sent(&self, output: &str) -> Vec<Vec<u8>> {
+        self.lock().sent.get(output).cloned().unwrap_or_default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl MidiBackend for MockBackend {
+    fn input_ports(&self) -> Result<Vec<String>, PianoError> {
+        Ok(self.lock().inputs.clone())
+    }
+
+    fn output_ports(&self) -> Result<Vec<String>, PianoError> {
+        Ok(self.lock().outputs.clone())
+    }
+
+    fn connect_input(
+        &self,
+        name: &str,
+        callback: InputCallback
+    ) -> Result<Box<dyn InputConnection>, PianoError> {
+        let mut state = self.lock();
+        if !state.inputs.iter().any(|input| input == name) {
+            return Err(PianoError::InvalidPort(name.to_string()));
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        let callback = Arc::new(Mutex::new(callback));
+        state.connected.entry(name.to_string()).or_default().push((id, Arc::clone(&callback)));
+        let script = state.scripts.remove(name).unwrap_or_default();
+        drop(state);
+
+        let mut callback = callback.lock().unwrap_or_else(PoisonError::into_inner);
+        for (stamp, message) in script {
+            callback(stamp, &message);
+        }
+
+        Ok(Box::new(MockInput { backend: self.clone(), name: name.to_string(), id }))
+    }
+
+    fn connect_output(&self, name: &str) -> Result<Box<dyn OutputConnection>, PianoError> {
+        if !self.lock().outputs.iter().any(|output| output == name) {
+            return Err(PianoError::InvalidPort(name.to_string()));
+        }
+
+        Ok(Box::new(MockOutput { backend: self.clone(), name: name.to_string() }))
+    }
+}
+
+struct MockInput {
+    backend: MockBackend,
+    name: String,
+    id: u64,
+}
+
+impl InputConnection for MockInput {
+    fn close(self: Box<Self>) {}
+}
+
+// Like midir, a dropped connection is closed as well
+impl Drop for MockInput {
+    fn drop(&mut self) {
+        if let Some(connected) = self.backend.lock().connected.get_mut(&self.name) {
+            connected.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+struct MockOutput {
+    backend: MockBackend,
+    name: String,
+}
+
+impl OutputConnection for MockOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), PianoError> {
+        self.backend.lock().sent.entry(self.name.clone()).or_default().push(message.to_vec());
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) {}
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::piano_listen::StateCode;
+use crate::profile::{ PianoFunction, Profiles };
+use crate::recording::{ Recording, SourceId };
+
+// A note counts as over once it has decayed by 60 dB
+const SILENCE: f32 = 6.9;
+
+/// Depths at which a continuous pedal switches on and back off, for consumers that need a plain
+/// pressed/released state. Keeping `off` below `on` stops a pedal resting near one threshold from
+/// flickering.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Thresholds {
+    pub on: f32,
+    pub off: f32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self { on: 0.5, off: 0.4 }
+    }
+}
+
+/// On/off state of a continuous pedal.
+#[derive(Debug, Clone, Copy)]
+pub struct PedalSwitch {
+    thresholds: Thresholds,
+    pressed: bool,
+}
+
+impl PedalSwitch {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Self { thresholds, pressed: false }
+    }
+
+    /// Feeds a new depth between 0 and 1 and returns whether the pedal is now pressed.
+    pub fn update(&mut self, depth: f32) -> bool {
+        if self.pressed && depth <= self.thresholds.off {
+            self.pressed = false;
+        } else if !self.pressed && depth >= self.thresholds.on {
+            self.pressed = true;
+        }
+        self.pressed
+    }
+}
+
+/// How the dampers of an acoustic piano follow the sustain pedal.
+///
+/// Below `contact` the dampers rest fully on the strings and above `clear` they are lifted off.
+/// In between they only partly touch the strings (half-pedalling), so released notes die away
+/// slower than dry notes but faster than fully sustained ones.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Damping {
+    pub contact: f32,
+    pub clear: f32,
+    /// Time for a released note to fade out with the dampers on the strings
+    pub dry_release_ms: u64,
+    /// Time for a released note to fade out with the dampers lifted
+    pub sustained_release_ms: u64,
+}
+
+impl Default for Damping {
+    fn default() -> Self {
+        Self {
+            contact: 0.2,
+            clear: 0.6,
+            dry_release_ms: 150,
+            sustained_release_ms: 12_000,
+        }
+    }
+}
+
+impl Damping {
+    /// How firmly the dampers hold the strings at `depth`, from 1 (fully damped) to 0 (free).
+    pub fn at(&self, depth: f32) -> f32 {
+        if self.clear <= self.contact {
+            return if depth >= self.clear { 0.0 } else { 1.0 };
+        }
+
+        let lifted = ((depth - self.contact) / (self.clear - self.contact)).clamp(0.0, 1.0);
+        // Smoothstep, the dampers start and finish leaving the strings gradually
+        1.0 - lifted * lifted * (3.0 - 2.0 * lifted)
+    }
+
+    /// Decay rate of a released note at `depth`, in nepers per second.
+    fn rate(&self, depth: f32) -> f32 {
+        let damping = self.at(depth);
+        let fade = (self.dry_release_ms as f32) * damping +
+            (self.sustained_release_ms as f32) * (1.0 - damping);
+        let fade = fade / 1000.0;
+        SILENCE / fade.max(f32::EPSILON)
+    }
+}
+
+/// A note of a recording, from key press until it has faded out.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NoteSpan {
+    pub source: SourceId,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+    pub start: Duration,
+    pub release: Duration,
+    pub end: Duration,
+}
+
+/// Works out how long every note of `recording` sounds, following the depth of the sustain pedal
+/// through half-pedalling. The pedal mapping and damping come from the profile of each source.
+///
+/// A released note decays at the rate set by the current pedal depth, which can change while it
+/// is fading out, and is over once it has decayed to silence or its key is struck again.
+pub fn note_spans(recording: &Recording, profiles: &Profiles) -> Vec<NoteSpan> {
+    let profile = |source: SourceId| profiles.for_port(recording.source_name(source).unwrap_or_default());
+    let rate = |source: SourceId, depth: &HashMap<SourceId, f32>| {
+        profile(source).damping.rate(depth.get(&source).copied().unwrap_or_default())
+    };
+
+    let mut depth: HashMap<SourceId, f32> = HashMap::new();
+    let mut held: HashMap<(SourceId, u8, u8), (Duration, u8)> = HashMap::new();
+    // Released notes that are still sounding, with how far they have decayed so far
+    let mut fading: Vec<(NoteSpan, f32)> = Vec::new();
+    let mut spans = Vec::new();
+    let mut last = Duration::ZERO;
+
+    for (at, source, message) in &recording.recording {
+        if message.len() < 3 {
+            continue;
+        }
+
+        // Decay the fading notes up to now, each at the depth of its own source's pedal
+        let previous = last;
+        let elapsed = at.saturating_sub(previous).as_secs_f32();
+        last = *at;
+        fading.retain_mut(|(span, decayed)| {
+            let rate = rate(span.source, &depth);
+            if *decayed + rate * elapsed >= SILENCE {
+                let remaining = ((SILENCE - *decayed) / rate).max(0.0);
+                span.end = previous + Duration::from_secs_f32(remaining);
+                spans.push(*span);
+                false
+            } else {
+                *decayed += rate * elapsed;
+                true
+            }
+        });
+
+        let status = message[0] & 0xf0;
+        let channel = message[0] & 0x0f;
+        let key = (*source, channel, message[1]);
+
+        if status == (StateCode::FunctionBegin as u8) {
+            if profile(*source).function(channel, message[1]) == Some(PianoFunction::RightPedal) {
+                depth.insert(*source, (message[2] as f32) / 127.0);
+            }
+        } else if status == (StateCode::KeyPress as u8) && message[2] > 0 {
+            // Striking a key again stops it if it was still ringing
+            fading.retain(|(span, _)| {
+                let ringing = (span.source, span.channel, span.note) == key;
+                if ringing {
+                    spans.push(NoteSpan { end: *at, ..*span });
+                }
+                !ringing
+            });
+            held.insert(key, (*at, message[2]));
+        } else if status == (StateCode::KeyPress as u8) || status == (StateCode::KeyRelease as u8) {
+            if let Some((start, velocity)) = held.remove(&key) {
+                let span = NoteSpan {
+                    source: *source,
+                    channel,
+                    note: message[1],
+                    velocity,
+                    start,
+                    release: *at,
+                    end: *at,
+                };
+                fading.push((span, 0.0));
+            }
+        }
+    }
+
+    // Whatever is left fades out after the recording, at the last pedal depth
+    for (mut span, decayed) in fading {
+        let rate = rate(span.source, &depth);
+        span.end = last + Duration::from_secs_f32(((SILENCE - decayed) / rate).max(0.0));
+        spans.push(span);
+    }
+    for ((source, channel, note), (start, velocity)) in held {
+        spans.push(NoteSpan { source, channel, note, velocity, start, release: last, end: last });
+    }
+
+    spans.sort_by_key(|span| (span.start, span.note));
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn piano(chunks: Vec<(u64, Vec<u8>)>) -> Recording {
+        let chunks = chunks.into_iter().map(|(at, message)| (ms(at), 0, message)).collect();
+        Recording::from(vec!["Piano".to_string()], chunks)
+    }
+
+    #[test]
+    fn switch_has_hysteresis_between_the_thresholds() {
+        let mut switch = PedalSwitch::new(Thresholds::default());
+
+        assert!(!switch.update(0.45));
+        assert!(switch.update(0.5));
+        // Between the thresholds the pedal keeps its state either way
+        assert!(switch.update(0.45));
+        assert!(!switch.update(0.4));
+        assert!(!switch.update(0.45));
+    }
+
+    #[test]
+    fn dampers_partly_touch_the_strings_at_half_pedal() {
+        let damping = Damping::default();
+
+        assert_eq!(damping.at(0.1), 1.0);
+        assert!((damping.at(0.4) - 0.5).abs() < 1e-4);
+        assert_eq!(damping.at(0.8), 0.0);
+        assert!(damping.rate(0.0) > damping.rate(0.4));
+        assert!(damping.rate(0.4) > damping.rate(1.0));
+    }
+
+    #[test]
+    fn sustain_pedal_extends_released_notes() {
+        let profiles = Profiles::builtin();
+        let dry = piano(vec![(0, vec![0x90, 60, 100]), (100, vec![0x80, 60, 0])]);
+        let sustained = piano(vec![
+            (0, vec![0x90, 60, 100]),
+            (50, vec![0xb0, 64, 127]),
+            (100, vec![0x80, 60, 0]),
+        ]);
+
+        let dry = note_spans(&dry, &profiles);
+        let sustained = note_spans(&sustained, &profiles);
+
+        assert_eq!(dry.len(), 1);
+        assert_eq!(dry[0].release, ms(100));
+        assert!(dry[0].end.abs_diff(ms(250)) < ms(1));
+        assert_eq!(sustained.len(), 1);
+        assert_eq!(sustained[0].release, ms(100));
+        assert!(sustained[0].end.abs_diff(ms(12_100)) < ms(1));
+    }
+
+    #[test]
+    fn striking_a_ringing_key_again_ends_it() {
+        let spans = note_spans(
+            &piano(vec![
+                (0, vec![0xb0, 64, 127]),
+                (10, vec![0x90, 60, 100]),
+                (20, vec![0x80, 60, 0]),
+                (500, vec![0x90, 60, 80]),
+                (600, vec![0x80, 60, 0]),
+            ]),
+            &Profiles::builtin()
+        );
+
+        assert_eq!(spans.len(), 2);
+        assert_eq!((spans[0].start, spans[0].end), (ms(10), ms(500)));
+        assert_eq!(spans[1].velocity, 80);
+    }
+}
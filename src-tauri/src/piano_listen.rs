@@ -1,14 +1,45 @@
-use std::error::Error;
+use std::collections::HashMap;
 use std::io::{ stdin, stdout, Write };
+use std::panic::{ catch_unwind, AssertUnwindSafe };
 use std::sync::{ Arc, Mutex };
 use std::thread::sleep;
 use std::time::{ Duration, Instant };
 
-use crossbeam_channel::Receiver;
-use midir::{ Ignore, MidiInput, MidiOutput, MidiOutputPort };
+use crossbeam_channel::{ bounded, never, select, unbounded, Receiver, RecvTimeoutError, Sender };
 use serde::Serialize;
 
-use crate::Recording;
+use crate::arpeggiator::{ ArpSettings, ArpSync, Arpeggiator };
+use crate::backend::{ Backend, OutputConnection };
+use crate::capture::CaptureBuffer;
+use crate::chords::{ ChordMemory, ChordPlayer };
+use crate::clock::{ ClockMaster, ClockOutput, TransportControl, TransportEvent };
+use crate::devices::DeviceEvent;
+use crate::error::PianoError;
+use crate::journal::Journal;
+use crate::metronome::{ Metronome, PULSES_PER_BEAT };
+use crate::pedal::PedalSwitch;
+use crate::profile::{ DeviceProfile, PianoFunction, Profiles };
+use crate::recording::{ Recording, SourceId, Timeline };
+use crate::routing::{ Route, Router };
+use crate::scale::ScaleControl;
+use crate::scheduler::{ MonotonicClock, Scheduler };
+use crate::timecode::{ generate, TimecodeReader, TimecodeSync };
+use crate::transpose::{ moved, TransposeControl, Transposer };
+use crate::virtual_ports::{ VIRTUAL_INPUT, VIRTUAL_OUTPUT };
+use crate::velocity::VelocityCurve;
+use crate::zones::{ Zone, ZoneRouter };
+
+// Source name of the notes the arpeggiator plays
+const ARPEGGIATOR: &str = "Arpeggiator";
+
+// How long before a message is due playback stops sleeping and spin-waits instead
+const SPIN_BEFORE_SEND: Duration = Duration::from_micros(300);
+
+// How long a chased timecode can go quiet before playback takes it as stopped
+const CHASE_DROPOUT: Duration = Duration::from_millis(250);
+
+// How far a chased timecode can be from where it should be before playback takes it as a jump
+const CHASE_JUMP: Duration = Duration::from_millis(100);
 
 #[derive(Debug, Clone, Copy, Serialize)]
 pub enum ClientEventType {
@@ -16,133 +47,265 @@ pub enum ClientEventType {
     KeyRelease,
     Pedal,
     Ambience,
+    Transport,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct ClientPianoEvent {
     event_type: ClientEventType,
+    /// Name of the device the event came from
+    source: String,
     key_string: String,
     key_id: u8,
     intensity: f32,
+    /// Velocity of a key press as sent, 0 for anything else
+    velocity: u8,
+    high_resolution_velocity: Option<u16>,
+    /// Set on key presses outside the scale when the scale lock only highlights them
+    out_of_scale: bool,
+    /// What happened to the incoming clock, for transport events
+    transport: Option<TransportEvent>,
+    /// Whether a pedal counts as pressed, by the thresholds of its device profile
+    pressed: bool,
 }
 
 impl ClientPianoEvent {
     pub fn new(
         event_type: ClientEventType,
+        source: String,
         key_string: String,
         intensity: f32,
-        key_id: u8
+        key_id: u8,
+        velocity: Velocity
     ) -> Self {
-        Self { event_type, intensity, key_string, key_id }
+        Self {
+            event_type,
+            source,
+            intensity,
+            key_string,
+            key_id,
+            velocity: velocity.raw,
+            high_resolution_velocity: velocity.high_resolution,
+            out_of_scale: false,
+            transport: None,
+            pressed: false,
+        }
+    }
+
+    pub fn flagged(self, out_of_scale: bool) -> Self {
+        Self { out_of_scale, ..self }
     }
 }
 
 #[derive(Debug, Clone, Copy, Serialize)]
 pub enum PianoEvent {
-    KeyPress(PianoKeyCode, Percent),
+    KeyPress(PianoKeyCode, Percent, Velocity),
     KeyRelease(PianoKeyCode),
-    RightPedal(Percent),
-    MiddlePedal(bool),
-    LeftPedal(Percent),
+    /// Depth of a pedal along with whether it counts as pressed
+    RightPedal(Percent, bool),
+    MiddlePedal(Percent, bool),
+    LeftPedal(Percent, bool),
     SetAmbience(Percent),
+    Transport(TransportEvent),
 }
 
 impl PianoEvent {
-    pub fn to_client_event(&self) -> ClientPianoEvent {
+    pub fn to_client_event(&self, source: &str) -> ClientPianoEvent {
         let event_type = match self {
-            Self::KeyPress(_, _) => ClientEventType::KeyPress,
+            Self::KeyPress(_, _, _) => ClientEventType::KeyPress,
             Self::KeyRelease(_) => ClientEventType::KeyRelease,
-            Self::RightPedal(_) | Self::MiddlePedal(_) | Self::LeftPedal(_) =>
+            Self::RightPedal(_, _) | Self::MiddlePedal(_, _) | Self::LeftPedal(_, _) =>
                 ClientEventType::Pedal,
             Self::SetAmbience(_) => ClientEventType::Ambience,
+            Self::Transport(_) => ClientEventType::Transport,
         };
 
         let key_string = match self {
-            Self::KeyPress(key, _) | Self::KeyRelease(key) => key.to_key_name(),
+            Self::KeyPress(key, _, _) | Self::KeyRelease(key) => key.to_key_name(),
             _ => "".to_string(),
         };
 
         let key_id = match self {
-            Self::KeyPress(key, _) | Self::KeyRelease(key) => *key as u8,
+            Self::KeyPress(key, _, _) | Self::KeyRelease(key) => *key as u8,
             _ => 0,
         };
 
         let intensity = match self {
-            Self::KeyRelease(_) => 0.0,
-            | Self::RightPedal(percent)
-            | Self::LeftPedal(percent)
-            | Self::KeyPress(_, percent)
+            Self::KeyRelease(_) | Self::Transport(_) => 0.0,
+            | Self::RightPedal(percent, _)
+            | Self::MiddlePedal(percent, _)
+            | Self::LeftPedal(percent, _)
+            | Self::KeyPress(_, percent, _)
             | Self::SetAmbience(percent) => percent.0,
-            Self::MiddlePedal(bool) =>
-                match bool {
-                    true => 1.0,
-                    false => 0.0,
-                }
         };
 
-        ClientPianoEvent::new(event_type, key_string, intensity, key_id)
+        let velocity = match self {
+            Self::KeyPress(_, _, velocity) => *velocity,
+            _ => Velocity::new(0),
+        };
+
+        let transport = match self {
+            Self::Transport(event) => Some(*event),
+            _ => None,
+        };
+
+        let pressed = match self {
+            | Self::RightPedal(_, pressed)
+            | Self::MiddlePedal(_, pressed)
+            | Self::LeftPedal(_, pressed) => *pressed,
+            _ => false,
+        };
+
+        ClientPianoEvent {
+            transport,
+            pressed,
+            ..ClientPianoEvent::new(event_type, source.to_string(), key_string, intensity, key_id, velocity)
+        }
+    }
+
+    /// The MIDI message for a key event on `channel`, `None` for anything else.
+    pub fn to_message(&self, channel: u8) -> Option<Vec<u8>> {
+        match self {
+            Self::KeyPress(key, _, velocity) => {
+                Some(vec![(StateCode::KeyPress as u8) | (channel & 0x0f), *key as u8, velocity.raw])
+            }
+            Self::KeyRelease(key) => {
+                Some(vec![(StateCode::KeyRelease as u8) | (channel & 0x0f), *key as u8, 0])
+            }
+            _ => None,
+        }
     }
 
+    /// Builds the event for a key message, its velocity going through the curve of `profile`.
     pub fn new(
         key_code: PianoKeyCode,
         state_code: StateCode,
-        alpha: Alpha
-    ) -> Result<Self, String> {
+        alpha: Alpha,
+        profile: &DeviceProfile
+    ) -> Result<Self, PianoError> {
         if (state_code as u8) == (StateCode::KeyPress as u8) {
-            Ok(Self::KeyPress(key_code, Percent::new(alpha)))
+            let velocity = Velocity::new(alpha.0);
+            Ok(Self::KeyPress(key_code, Percent::with_curve(velocity, &profile.velocity_curve), velocity))
         } else if (state_code as u8) == (StateCode::KeyRelease as u8) {
             Ok(Self::KeyRelease(key_code))
-        } else if (state_code as u8) == (StateCode::FunctionBegin as u8) {
-            match key_code {
-                PianoKeyCode::E4 => Ok(Self::RightPedal(Percent::new(alpha))),
-                PianoKeyCode::Gb4 => {
-                    if alpha.0 == 127 {
-                        Ok(Self::MiddlePedal(true))
-                    } else {
-                        Ok(Self::MiddlePedal(false))
-                    }
-                }
-                PianoKeyCode::G4 => Ok(Self::LeftPedal(Percent::new(alpha))),
-                PianoKeyCode::G6 => Ok(Self::SetAmbience(Percent::new(alpha))),
-                k => unreachable!("Unkown event 1: {:?}", k),
-            }
-        } else if (state_code as u8) == (StateCode::FunctionRelease as u8) {
-            match key_code {
-                PianoKeyCode::G6 => { Ok(Self::SetAmbience(Percent::new(alpha))) }
-                k => { Err(format!("Unkown event 2: {:?}", k)) }
-            }
-        } else if (state_code as u8) == (StateCode::Other as u8) {
-            match key_code {
-                PianoKeyCode::G6 => { Ok(Self::SetAmbience(Percent::new(alpha))) }
-                k => { Err(format!("Unkown event 2: {:?}", k)) }
-            }
         } else {
-            Err(format!("Unhandled event"))
+            Err(PianoError::UnhandledMessage(format!("{:?} is not a key message", state_code)))
+        }
+    }
+
+    /// Builds the pedal or ambience event `profile` maps the controller to on `channel`. A pedal
+    /// counts as pressed from the `on` threshold of the profile, see `Decoder` for switching back
+    /// off at the `off` one.
+    pub fn from_control_change(
+        channel: u8,
+        change: ControlChange,
+        profile: &DeviceProfile
+    ) -> Result<Self, PianoError> {
+        let depth = Percent::new(Alpha(change.value));
+        let pressed = depth.0 >= profile.pedal_thresholds.on;
+        match profile.function(channel, change.controller.number()) {
+            Some(PianoFunction::RightPedal) => Ok(Self::RightPedal(depth, pressed)),
+            Some(PianoFunction::MiddlePedal) => Ok(Self::MiddlePedal(depth, pressed)),
+            Some(PianoFunction::LeftPedal) => Ok(Self::LeftPedal(depth, pressed)),
+            Some(PianoFunction::Ambience) => Ok(Self::SetAmbience(depth)),
+            None => {
+                Err(
+                    PianoError::UnhandledMessage(
+                        format!("Unmapped {:?} on channel {}", change.controller, channel + 1)
+                    )
+                )
+            }
+        }
+    }
+}
+
+/// Controller numbers, with names for the well-known ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Controller {
+    Expression,
+    Sustain,
+    Sostenuto,
+    Soft,
+    HighResolutionVelocity,
+    ReverbDepth,
+    AllNotesOff,
+    Other(u8),
+}
+
+impl Controller {
+    pub fn number(self) -> u8 {
+        match self {
+            Self::Expression => 11,
+            Self::Sustain => 64,
+            Self::Sostenuto => 66,
+            Self::Soft => 67,
+            Self::HighResolutionVelocity => 88,
+            Self::ReverbDepth => 91,
+            Self::AllNotesOff => 123,
+            Self::Other(number) => number,
+        }
+    }
+}
+
+impl From<u8> for Controller {
+    fn from(number: u8) -> Self {
+        match number {
+            11 => Self::Expression,
+            64 => Self::Sustain,
+            66 => Self::Sostenuto,
+            67 => Self::Soft,
+            88 => Self::HighResolutionVelocity,
+            91 => Self::ReverbDepth,
+            123 => Self::AllNotesOff,
+            number => Self::Other(number),
         }
     }
 }
 
+/// A control change message, without its channel.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ControlChange {
+    pub controller: Controller,
+    pub value: u8,
+}
+
+impl ControlChange {
+    pub fn new(controller: u8, value: u8) -> Self {
+        Self { controller: controller.into(), value }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize)]
 pub enum StateCode {
     KeyPress = 144,
     KeyRelease = 128,
     FunctionBegin = 176,
-    FunctionRelease = 178,
-    Other = 181,
+    SongPosition = 242,
+    Clock = 248,
+    Start = 250,
+    Continue = 251,
+    Stop = 252,
 }
 
-impl From<u8> for StateCode {
-    fn from(key: u8) -> Self {
-        match key {
+impl TryFrom<u8> for StateCode {
+    type Error = PianoError;
+
+    fn try_from(key: u8) -> Result<Self, Self::Error> {
+        let state_code = match key {
             144 => Self::KeyPress,
             128 => Self::KeyRelease,
             176 => Self::FunctionBegin,
-            178 => Self::FunctionRelease,
-            181 => Self::Other,
+            242 => Self::SongPosition,
+            248 => Self::Clock,
+            250 => Self::Start,
+            251 => Self::Continue,
+            252 => Self::Stop,
             s => {
-                panic!("Invalid State Code: {}", s);
+                return Err(PianoError::UnhandledMessage(format!("invalid state code {}", s)));
             }
-        }
+        };
+
+        Ok(state_code)
     }
 }
 
@@ -153,10 +316,41 @@ impl Percent {
     pub fn new(alpha: Alpha) -> Self {
         Self((alpha.0 as f32) / 127.0)
     }
+
+    pub fn with_curve(velocity: Velocity, curve: &VelocityCurve) -> Self {
+        Self(curve.apply_normalized(velocity.normalized()))
+    }
+}
+
+/// Key velocity exactly as the keyboard sent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Velocity {
+    /// The 7-bit velocity of the note-on message
+    pub raw: u8,
+    /// 14-bit velocity, when the note-on came with a CC 88 prefix holding the low bits
+    pub high_resolution: Option<u16>,
+}
+
+impl Velocity {
+    pub fn new(raw: u8) -> Self {
+        Self { raw, high_resolution: None }
+    }
+
+    pub fn with_low_bits(raw: u8, low_bits: u8) -> Self {
+        Self { raw, high_resolution: Some((((raw & 0x7f) as u16) << 7) | ((low_bits & 0x7f) as u16)) }
+    }
+
+    /// The velocity between 0 and 1, as precise as the keyboard sent it.
+    pub fn normalized(self) -> f32 {
+        match self.high_resolution {
+            Some(velocity) => (velocity as f32) / 16383.0,
+            None => (self.raw as f32) / 127.0,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct Alpha(u8);
+pub struct Alpha(pub u8);
 
 #[derive(Debug, Clone, Copy, Serialize)]
 pub enum PianoKeyCode {
@@ -369,9 +563,11 @@ impl PianoKeyCode {
     }
 }
 
-impl From<u8> for PianoKeyCode {
-    fn from(key: u8) -> Self {
-        match key {
+impl TryFrom<u8> for PianoKeyCode {
+    type Error = PianoError;
+
+    fn try_from(key: u8) -> Result<Self, Self::Error> {
+        let key_code = match key {
             15 => Self::Eb0,
             16 => Self::E0,
             17 => Self::F0,
@@ -471,150 +667,1241 @@ impl From<u8> for PianoKeyCode {
             111 => Self::Eb8,
             112 => Self::E8,
             113 => Self::F8,
-            k => panic!("Invalid Piano Key Code: {}", k),
-        }
+            k => {
+                return Err(PianoError::UnhandledMessage(format!("no piano key {}", k)));
+            }
+        };
+
+        Ok(key_code)
     }
 }
 
 impl PianoKeyCode {
-    pub fn new(key: u8) -> Self {
-        key.into()
+    /// The key with MIDI note number `key`, `None` if the piano doesn't have it.
+    pub fn checked(key: u8) -> Option<Self> {
+        Self::try_from(key).ok()
+    }
+}
+
+/// What `listen` does with incoming messages besides passing them on to the handler.
+#[derive(Default)]
+pub struct ListenOptions {
+    /// Names of the input ports to listen to, if empty the only available port is used (or one
+    /// picked on the console when there are several)
+    pub ports: Vec<String>,
+    pub record: bool,
+    pub capture: Option<Arc<Mutex<CaptureBuffer>>>,
+    pub journal: Option<Journal>,
+    /// Profiles to pick from by port name, for decoding pedals and other functions
+    pub profiles: Profiles,
+    /// Output ports to forward the incoming messages to as they arrive
+    pub routes: Vec<Route>,
+    /// Port changes, used to reconnect when a port disappears and comes back
+    pub devices: Option<Receiver<DeviceEvent>>,
+    /// Applied to the notes before they are decoded and routed, but not to the recording
+    pub transpose: TransposeControl,
+    /// Snaps or mutes notes outside a scale, after the transpose
+    pub scale: ScaleControl,
+    /// Ranges of keys to play on their own outputs
+    pub zones: Vec<Zone>,
+    /// Plays the held keys as a pattern instead of directly
+    pub arpeggiator: Option<ArpSettings>,
+    /// Turns single keys into memorized chords while enabled
+    pub chords: ChordMemory,
+    /// Sends MIDI clock on an output port while listening, for other gear to follow
+    pub clock_output: Option<ClockOutput>,
+    /// Where the ports are opened
+    pub backend: Backend,
+}
+
+// An input port being listened to
+struct Input {
+    timeline: Timeline,
+    source: SourceId,
+    capture_source: Option<SourceId>,
+}
+
+// Everything the input callbacks keep between messages. It lives outside the callbacks so it
+// carries over when a port is reconnected.
+struct InputState {
+    inputs: Vec<Input>,
+    recording: Option<Recording>,
+    journal: Option<Journal>,
+    capture: Option<Arc<Mutex<CaptureBuffer>>>,
+    /// Where this listener's times start in the capture buffer
+    capture_offset: Duration,
+}
+
+impl InputState {
+    // Records the message, failing only when the journal can't be written anymore. The recording
+    // carries on without the journal then, but is no longer safe from a crash.
+    fn receive(&mut self, input: usize, stamp: u64, message: &[u8]) -> Result<(), PianoError> {
+        let input = &mut self.inputs[input];
+        let at = input.timeline.at(stamp);
+        let mut outcome = Ok(());
+        if let Some(recording) = &mut self.recording {
+            let chunk = (at, input.source, message.to_vec());
+            if let Some(journal) = &mut self.journal {
+                if let Err(e) = journal.append(&chunk) {
+                    outcome = Err(journal_failed(e));
+                    self.journal = None;
+                }
+            }
+            recording.push(chunk);
+        }
+        if let (Some(capture), Some(source)) = (&self.capture, input.capture_source) {
+            capture.lock().unwrap().push(self.capture_offset + at, source, message.to_vec());
+        }
+
+        outcome
+    }
+}
+
+fn journal_failed(e: std::io::Error) -> PianoError {
+    PianoError::Journal(format!("{}, recording continues without it", e))
+}
+
+// Where the messages of an input go once they are transposed: the routes, the zones and the
+// handler
+struct Outlet<F> {
+    name: String,
+    router: Option<Arc<Mutex<Router>>>,
+    zones: Option<Arc<Mutex<ZoneRouter>>>,
+    zone_transposers: Vec<Transposer>,
+    handler: Arc<F>,
+    error_sender: Sender<PianoError>,
+}
+
+impl<F> Outlet<F> where F: Fn(&str, Result<PianoEvent, PianoError>) {
+    // `raw` is the message as played, `message` the transposed one if it wasn't dropped
+    // `mapping` is what the live input does to a note, zones pick their notes by `raw` and apply it
+    // themselves
+    fn route(&mut self, raw: &[u8], message: Option<&[u8]>, mapping: &dyn Fn(u8) -> Option<u8>) {
+        if let (Some(router), Some(message)) = (&self.router, message) {
+            router.lock().unwrap().send(&self.name, message);
+        }
+        if let Some(zones) = &self.zones {
+            zones.lock().unwrap().send(&mut self.zone_transposers, raw, mapping);
+        }
+    }
+
+    // Routes a key event that didn't come in as a message of its own, like the notes of a chord
+    fn route_event(&mut self, piano_event: &PianoEvent, channel: u8) {
+        if let Some(message) = piano_event.to_message(channel) {
+            // Already transposed, the key that played it was
+            self.route(&message, Some(&message), &Some);
+        }
+    }
+
+    fn emit(&self, piano_event: Result<PianoEvent, PianoError>) {
+        if catch_unwind(AssertUnwindSafe(|| (self.handler)(&self.name, piano_event))).is_err() {
+            let _ = self.error_sender.try_send(PianoError::Midi("event handler panicked".to_string()));
+        }
     }
 }
 
+// The arpeggiator along with the outlet its notes leave through, as if it were one more input
+struct ArpeggiatorOutlet<F> {
+    arpeggiator: Arpeggiator,
+    outlet: Outlet<F>,
+}
+
+impl<F> ArpeggiatorOutlet<F> where F: Fn(&str, Result<PianoEvent, PianoError>) {
+    fn pulse(&mut self) {
+        let piano_events = self.arpeggiator.pulse();
+        self.play(piano_events);
+    }
+
+    fn stop(&mut self) {
+        let piano_events = self.arpeggiator.stop();
+        self.play(piano_events);
+    }
+
+    // Keeps in step with the transport of the incoming clock
+    fn follow(&mut self, event: TransportEvent) {
+        match event {
+            TransportEvent::Start => self.arpeggiator.seek(0),
+            TransportEvent::Seek(position) => self.arpeggiator.seek(position),
+            TransportEvent::Stop => self.stop(),
+            _ => {}
+        }
+    }
+
+    fn play(&mut self, piano_events: Vec<PianoEvent>) {
+        let channel = self.arpeggiator.settings().channel;
+        for piano_event in piano_events {
+            self.outlet.route_event(&piano_event, channel);
+            self.outlet.emit(Ok(piano_event));
+        }
+    }
+}
+
+/// Listens to one or more input ports until a stop is requested, passing every event to `handler`
+/// along with the name of the port it came from.
+///
+/// The calling thread blocks until either a message arrives on `receiver` or the sender side of
+/// `receiver` is dropped, after which the connections are closed and the recording (if requested)
+/// is returned. If `handler` panics the connections are closed as well and an error returned.
+///
+/// All ports share one timeline, so the recording holds a single time-ordered stream with every
+/// chunk tagged with its source. Messages are also forwarded along `options.routes` as they arrive,
+/// after `options.transpose` has moved their notes and `options.scale` corrected them, and played
+/// on the outputs of `options.zones`.
+/// With `options.chords` enabled a single key plays a whole chord, and with `options.arpeggiator`
+/// set, keys are played as a pattern in time with the internal metronome or the incoming MIDI
+/// clock, through the same routes, zones and handler. When a port disappears the listener keeps
+/// going, and reconnects to it once it is back. Its part of the recording carries on where it left
+/// off.
+///
+/// Incoming MIDI clock moves the song along while the transport plays, starting over on a start
+/// and jumping on a song position pointer. The handler gets the transport messages and a beat
+/// event every quarter note rather than every pulse. With `options.clock_output` set the listener
+/// is clock master on that port for as long as it runs.
 pub fn listen<F>(
     handler: F,
-    record: bool,
+    options: ListenOptions,
     receiver: Receiver<()>
-) -> Result<Option<Arc<Mutex<Recording>>>, Box<dyn Error + Send + Sync>>
-    where F: Fn(Result<PianoEvent, String>) + Send + 'static
+) -> Result<Option<Recording>, PianoError>
+    where F: Fn(&str, Result<PianoEvent, PianoError>) + Send + Sync + 'static
 {
-    let mut input = String::new();
+    let backend = options.backend;
+    let mut port_names: Vec<String> = Vec::new();
+    if options.ports.is_empty() {
+        port_names.push(select_input_port(&backend)?);
+    }
+    for name in options.ports {
+        if !port_names.contains(&name) {
+            port_names.push(name);
+        }
+    }
 
-    let mut midi_in = MidiInput::new("midir reading input")?;
-    midi_in.ignore(Ignore::None);
+    let capture_offset = options.capture
+        .as_ref()
+        .map(|capture| capture.lock().unwrap().resume())
+        .unwrap_or_default();
 
-    // Get an input port (read from console if multiple are available)
-    let in_ports = midi_in.ports();
-    let in_port = match in_ports.len() {
-        0 => {
-            return Err("no input port found".into());
+    let started = Instant::now();
+    let mut recording = options.record.then(Recording::new);
+    let mut inputs = Vec::new();
+    for (index, name) in port_names.iter().enumerate() {
+        let source = match &mut recording {
+            Some(recording) => recording.source(name),
+            None => index as SourceId,
+        };
+        let capture_source = options.capture.as_ref().map(|capture| capture.lock().unwrap().source(name));
+        inputs.push(Input { timeline: Timeline::starting_at(started), source, capture_source });
+    }
+
+    // The sources go first, in the order of their ids
+    let mut journal_error = None;
+    let journal = options.journal.and_then(|mut journal| {
+        match port_names.iter().try_for_each(|name| journal.add_source(name)) {
+            Ok(()) => Some(journal),
+            Err(e) => {
+                journal_error = Some(journal_failed(e));
+                None
+            }
         }
-        1 => {
-            println!(
-                "Choosing the only available input port: {}",
-                midi_in.port_name(&in_ports[0]).unwrap()
-            );
-            &in_ports[0]
+    });
+
+    let state = Arc::new(
+        Mutex::new(InputState { inputs, recording, journal, capture: options.capture, capture_offset })
+    );
+    let handler = Arc::new(handler);
+    let (error_sender, error_receiver) = bounded(1);
+
+    let router = Router::connect(&backend, options.routes)?;
+    let router = (!router.is_empty()).then(|| Arc::new(Mutex::new(router)));
+    let zones = ZoneRouter::connect(&backend, options.zones)?;
+    let zones = (!zones.is_empty()).then(|| Arc::new(Mutex::new(zones)));
+    let clock_master = match &options.clock_output {
+        Some(clock_output) => Some(ClockMaster::start(&backend, clock_output)?),
+        None => None,
+    };
+
+    let outlet = |name: &str| Outlet {
+        name: name.to_string(),
+        router: router.clone(),
+        zones: zones.clone(),
+        zone_transposers: zones
+            .as_ref()
+            .map(|zones| zones.lock().unwrap().transposers())
+            .unwrap_or_default(),
+        handler: Arc::clone(&handler),
+        error_sender: error_sender.clone(),
+    };
+    // Listening goes on without the journal, the UI only needs to know the take isn't safe
+    if let (Some(e), Some(name)) = (journal_error, port_names.first()) {
+        outlet(name).emit(Err(e));
+    }
+
+    let arpeggiator = match options.arpeggiator {
+        Some(settings) => {
+            settings.validate()?;
+            let arpeggiator = Arpeggiator::new(settings);
+            Some(Arc::new(Mutex::new(ArpeggiatorOutlet { arpeggiator, outlet: outlet(ARPEGGIATOR) })))
         }
-        _ => {
-            println!("\nAvailable input ports:");
-            for (i, p) in in_ports.iter().enumerate() {
-                println!("{}: {}", i, midi_in.port_name(p).unwrap());
+        None => None,
+    };
+    let arpeggiator_sync = arpeggiator
+        .as_ref()
+        .map(|arpeggiator| arpeggiator.lock().unwrap().arpeggiator.settings().sync);
+    let transport = TransportControl::default();
+
+    let callback = |input: usize| {
+        let state = Arc::clone(&state);
+        let arpeggiator = arpeggiator.clone();
+        let transport = transport.clone();
+        let name = port_names[input].clone();
+        let mut outlet = outlet(&name);
+        let mut decoder = Decoder::new(options.profiles.for_port(&name).clone());
+        let transpose = options.transpose.clone();
+        let scale = options.scale.clone();
+        let mut transposer = Transposer::new();
+        let mut chords = ChordPlayer::new(options.chords.clone());
+        move |stamp, raw: &[u8]| {
+            let shift = transpose.get().shift();
+            let scale_lock = scale.get();
+            let mapping = |note| moved(note, shift).and_then(|note| scale_lock.map(note));
+            let message = transposer.map(raw, mapping);
+            let piano_event = message.as_deref().and_then(|message| decoder.decode(message).transpose());
+            // One key can stand for a whole chord
+            let chord = match &piano_event {
+                Some(Ok(piano_event)) => chords.play(piano_event),
+                _ => None,
+            };
+            let channel = raw.first().map_or(0, |status| status & 0x0f);
+
+            // Keys go to the arpeggiator, which plays them in its own time
+            let arpeggiated = match (&arpeggiator, &piano_event, &chord) {
+                (Some(arpeggiator), _, Some(notes)) => {
+                    let mut arpeggiator = arpeggiator.lock().unwrap();
+                    notes.iter().for_each(|note| {
+                        arpeggiator.arpeggiator.feed(note);
+                    });
+                    true
+                }
+                (Some(arpeggiator), Some(Ok(piano_event)), None) => {
+                    arpeggiator.lock().unwrap().arpeggiator.feed(piano_event)
+                }
+                _ => false,
+            };
+            // Routed first, it's what the player hears
+            if !arpeggiated {
+                match &chord {
+                    Some(notes) => notes.iter().for_each(|note| outlet.route_event(note, channel)),
+                    None => outlet.route(raw, message.as_deref(), &mapping),
+                }
+            }
+            // Recorded as played, so the transpose can still be changed afterwards
+            let received = state.lock().unwrap().receive(input, stamp, raw);
+            if let Err(e) = received {
+                outlet.emit(Err(e));
+            }
+
+            if let Some(Ok(PianoEvent::Transport(event))) = piano_event {
+                let position = transport.follow(event, stamp);
+                if let (Some(arpeggiator), Some(ArpSync::MidiClock)) = (&arpeggiator, arpeggiator_sync) {
+                    let mut arpeggiator = arpeggiator.lock().unwrap();
+                    arpeggiator.follow(event);
+                    if position.is_some() {
+                        arpeggiator.pulse();
+                    }
+                }
+                // The UI keeps time by the beat, 24 pulses a beat would only swamp it
+                match (event, position) {
+                    (TransportEvent::Pulse, Some(position)) if position % PULSES_PER_BEAT == 0 => {
+                        let beat = TransportEvent::Beat(position / PULSES_PER_BEAT);
+                        outlet.emit(Ok(PianoEvent::Transport(beat)));
+                    }
+                    (TransportEvent::Pulse, _) => {}
+                    (event, _) => outlet.emit(Ok(PianoEvent::Transport(event))),
+                }
+                return;
+            }
+            if arpeggiated {
+                return;
+            }
+            match (chord, piano_event) {
+                (Some(notes), _) => notes.into_iter().for_each(|note| outlet.emit(Ok(note))),
+                (None, Some(piano_event)) => outlet.emit(piano_event),
+                (None, None) => {}
+            }
+        }
+    };
+
+    for name in &port_names {
+        println!("Using the '{}' profile for '{}'", options.profiles.for_port(name).name, name);
+    }
+
+    println!("\nOpening connections");
+    let mut connections = port_names
+        .iter()
+        .enumerate()
+        .map(|(input, name)| backend.connect_input(name, Box::new(callback(input))).map(Some))
+        .collect::<Result<Vec<_>, _>>()?;
+    println!("Connections open, reading input from {:?} ...", port_names);
+
+    let metronome = match (&arpeggiator, arpeggiator_sync) {
+        (Some(arpeggiator), Some(ArpSync::Internal(bpm))) => {
+            let arpeggiator = Arc::clone(arpeggiator);
+            Some(Metronome::start(bpm, move || arpeggiator.lock().unwrap().pulse()))
+        }
+        _ => None,
+    };
+
+    let devices = options.devices.unwrap_or_else(never);
+
+    // A dropped stop sender counts as a stop request, so an abandoned listener can't linger
+    let outcome = loop {
+        select! {
+            recv(receiver) -> _ => break Ok(()),
+            recv(error_receiver) -> error => {
+                break Err(error.unwrap_or_else(|_| PianoError::Midi("input connection failed".to_string())));
+            }
+            recv(devices) -> event => match event {
+                Ok(DeviceEvent::Disconnected(name)) => {
+                    let input = port_names.iter().position(|port_name| *port_name == name);
+                    if let Some(connection) = input.and_then(|input| connections[input].take()) {
+                        connection.close();
+                        println!("'{}' disconnected, waiting for it to come back", name);
+                    }
+                }
+                Ok(DeviceEvent::Connected(name)) => {
+                    let input = port_names
+                        .iter()
+                        .position(|port_name| *port_name == name)
+                        .filter(|input| connections[*input].is_none());
+                    if let Some(input) = input {
+                        // The driver timestamps of the new connection start over
+                        state.lock().unwrap().inputs[input].timeline.reanchor();
+                        match backend.connect_input(&name, Box::new(callback(input))) {
+                            Ok(connection) => {
+                                println!("Reconnected to '{}'", name);
+                                connections[input] = Some(connection);
+                            }
+                            Err(e) => outlet(&name).emit(Err(e)),
+                        }
+                    }
+                }
+                Err(_) => break Err(PianoError::Midi("device watcher stopped".to_string())),
             }
-            print!("Please select input port: ");
-            stdout().flush()?;
-            let mut input = String::new();
-            stdin().read_line(&mut input)?;
-            in_ports.get(input.trim().parse::<usize>()?).ok_or("invalid input port selected")?
         }
     };
 
+    println!("Closing connections");
+    for connection in connections.into_iter().flatten() {
+        connection.close();
+    }
+    if let Some(metronome) = metronome {
+        metronome.stop();
+    }
+    if let Some(clock_master) = clock_master {
+        clock_master.stop();
+    }
+    // Lets go of the last note while its outlet is still open
+    if let Some(arpeggiator) = arpeggiator {
+        arpeggiator.lock().unwrap().stop();
+    }
+    if let Some(router) = router.and_then(Arc::into_inner) {
+        router.into_inner().unwrap().close();
+    }
+    if let Some(zones) = zones.and_then(Arc::into_inner) {
+        zones.into_inner().unwrap().close();
+    }
+
+    outcome?;
+    let recording = state.lock().unwrap().recording.take();
+    Ok(recording)
+}
+
+/// Collects the velocities of the next `count` key presses on `port`, or of as many as were played
+/// when `timeout` runs out.
+pub fn sample_velocities(
+    backend: &Backend,
+    port: &str,
+    count: usize,
+    timeout: Duration
+) -> Result<Vec<u8>, PianoError> {
+    let (sender, receiver) = bounded(count);
+    let connection = backend.connect_input(
+        port,
+        Box::new(move |_, message: &[u8]| {
+            let is_press = message.len() >= 3 &&
+                (message[0] & 0xf0) == (StateCode::KeyPress as u8) &&
+                message[2] > 0;
+            if is_press {
+                let _ = sender.try_send(message[2]);
+            }
+        })
+    )?;
+
+    let deadline = Instant::now() + timeout;
+    let mut velocities = Vec::new();
+    while velocities.len() < count {
+        match receiver.recv_deadline(deadline) {
+            Ok(velocity) => velocities.push(velocity),
+            Err(_) => {
+                break;
+            }
+        }
+    }
+    connection.close();
+
+    Ok(velocities)
+}
+
+#[derive(Default)]
+pub struct PlayOptions {
+    /// Name of the output port to play on, if not set the only available port is used (or one
+    /// picked on the console)
+    pub output: Option<String>,
+    /// Lines playback up with a video timeline
+    pub timecode: Option<TimecodeSync>,
+    /// Where the ports are opened
+    pub backend: Backend,
+}
+
+/// Plays `recording` on an output port, stopping early once anything arrives on `stop`.
+///
+/// With `options.timecode` set, playback lines up with a video timeline: it either sends MTC along
+/// with the recording on the same port, or chases the MTC coming in on an input port, following it
+/// as it starts, stops and jumps.
+pub fn play(recording: Recording, options: PlayOptions, stop: Receiver<()>) -> Result<(), PianoError> {
+    let PlayOptions { output, timecode, backend } = options;
+    if let Some(TimecodeSync::Generate { rate, start } | TimecodeSync::Chase { rate, start, .. }) =
+        &timecode
+    {
+        start.validate(*rate)?;
+    }
+
+    let output = match output {
+        Some(output) => output,
+        None => select_output_port(&backend)?,
+    };
     println!("\nOpening connection");
-    let in_port_name = midi_in.port_name(in_port)?;
-
-    let recording = Arc::new(Mutex::new(Recording::new())); // Wrap Recording in Arc<Mutex<_>>
-    let recording_clone = recording.clone(); // Create a clone for use in the closure
-    let mut now = Instant::now();
-
-    // _conn_in needs to be a named parameter, because it needs to be kept alive until the end of the scope
-    let _conn_in = midi_in.connect(
-        in_port,
-        "midir-read-input",
-        move |_, message: &[u8], _| {
-            if record {
-                recording_clone.lock().unwrap().push((now.elapsed(), message.to_vec()));
-                now = Instant::now();
-            }
-            let state_code = StateCode::from(message[0]);
-            let key_code = PianoKeyCode::from(message[1]);
-            let alpha = Alpha(message[2]);
-            let piano_event = PianoEvent::new(key_code, state_code, alpha);
-            handler(piano_event);
-        },
-        ()
+    let mut conn_out = backend.connect_output(&output)?;
+    println!("Connection open. Listen!");
+    let mut messages: Vec<(Duration, &[u8])> = recording.recording
+        .iter()
+        .map(|(at, _, record_chunk)| (*at, record_chunk.as_slice()))
+        .collect();
+    let length = messages.last().map_or(Duration::ZERO, |(at, _)| *at);
+    let timecode_messages = match &timecode {
+        Some(TimecodeSync::Generate { rate, start }) => generate(*start, *rate, length),
+        _ => Vec::new(),
+    };
+    messages.extend(timecode_messages.iter().map(|(at, message)| (*at, message.as_slice())));
+    // Stable, so the timecode goes out after the notes due at the same time
+    messages.sort_by_key(|(at, _)| *at);
+
+    match &timecode {
+        Some(TimecodeSync::Chase { input, rate, start }) => {
+            let start = rate.frame_time(start.to_frame(*rate));
+            chase(&backend, &messages, input, start, conn_out.as_mut(), &stop)?;
+        }
+        _ => {
+            let scheduler = Scheduler::new(MonotonicClock::new(), SPIN_BEFORE_SEND);
+            for (at, message) in &messages {
+                if scheduler.wait_until_or_stopped(*at, &stop).is_none() {
+                    break;
+                }
+                let _ = conn_out.send(message);
+            }
+        }
+    }
+    sleep(Duration::from_millis(150));
+    println!("\nClosing connection");
+    // This is optional, the connection would automatically be closed as soon as it goes out of scope
+    conn_out.close();
+    println!("Connection closed");
+    Ok(())
+}
+
+// Plays `messages` in time with the MTC coming in on `input`, the first of them being due at
+// `start` on its timeline. Between timecodes it goes by its own clock, and it holds while the
+// timecode stops coming.
+fn chase(
+    backend: &Backend,
+    messages: &[(Duration, &[u8])],
+    input: &str,
+    start: Duration,
+    conn_out: &mut dyn OutputConnection,
+    stop: &Receiver<()>
+) -> Result<(), PianoError> {
+    let (sender, positions) = unbounded();
+    let mut reader = TimecodeReader::default();
+    let connection = backend.connect_input(
+        input,
+        Box::new(move |_, message: &[u8]| {
+            if let Some(position) = reader.read(message) {
+                let _ = sender.send((Instant::now(), position));
+            }
+        })
     )?;
 
-    println!("Connection open, reading input from '{}' (press enter to exit) ...", in_port_name);
+    let mut next = 0;
+    // Where the timeline was at the last timecode, `None` while it isn't running
+    let mut anchor: Option<(Instant, Duration)> = None;
+    while next < messages.len() {
+        let wait = match anchor {
+            Some((instant, position)) => {
+                let due = instant + (start + messages[next].0).saturating_sub(position);
+                due.saturating_duration_since(Instant::now()).min(CHASE_DROPOUT)
+            }
+            None => CHASE_DROPOUT,
+        };
+        select! {
+            recv(stop) -> _ => break,
+            recv(positions) -> update => if let Ok((instant, position)) = update {
+                let expected = anchor.map(|(at, from)| from + instant.duration_since(at));
+                let jumped = match expected {
+                    Some(expected) => expected.max(position) - expected.min(position) > CHASE_JUMP,
+                    None => true,
+                };
+                if jumped {
+                    all_notes_off(conn_out);
+                    next = messages.partition_point(|(at, _)| start + *at < position);
+                }
+                anchor = Some((instant, position));
+            },
+            default(wait) => {}
+        }
 
-    loop {
-        match receiver.try_recv() {
-            Ok(()) => {
-                break;
+        match anchor {
+            Some((instant, _)) if instant.elapsed() > CHASE_DROPOUT => {
+                // The timeline stopped, nothing should be left sounding until it moves again
+                all_notes_off(conn_out);
+                anchor = None;
             }
-            _ => {}
+            Some((instant, position)) => {
+                let now = position + instant.elapsed();
+                while next < messages.len() && start + messages[next].0 <= now {
+                    let _ = conn_out.send(messages[next].1);
+                    next += 1;
+                }
+            }
+            None => {}
+        }
+    }
+    connection.close();
+
+    Ok(())
+}
+
+fn all_notes_off(conn_out: &mut dyn OutputConnection) {
+    for channel in 0..16 {
+        let status = (StateCode::FunctionBegin as u8) | channel;
+        let _ = conn_out.send(&[status, Controller::AllNotesOff.number(), 0]);
+    }
+}
+
+/// Re-records the range between `punch_in` and `punch_out` of `recording`.
+///
+/// The recording is played back up to the punch-in point, after which live input is captured
+/// until the punch-out point and spliced into a copy of the recording, which is returned. A stop
+/// on `stop` (or its sender being dropped) before the punch-out point abandons the take, and
+/// `None` is returned.
+pub fn punch_record<F>(
+    backend: &Backend,
+    recording: &Recording,
+    punch_in: Duration,
+    punch_out: Duration,
+    profiles: &Profiles,
+    handler: F,
+    stop: Receiver<()>
+) -> Result<Option<Recording>, PianoError>
+    where F: Fn(&str, Result<PianoEvent, PianoError>) + Send + 'static
+{
+    if punch_out <= punch_in {
+        return Err(PianoError::InvalidPunchRange);
+    }
+
+    let out_port_name = select_output_port(backend)?;
+    let in_port_name = select_input_port(backend)?;
+    let mut decoder = Decoder::new(profiles.for_port(&in_port_name).clone());
+
+    let mut conn_out = backend.connect_output(&out_port_name)?;
+    let scheduler = Scheduler::new(MonotonicClock::new(), SPIN_BEFORE_SEND);
+    for (at, _, record_chunk) in &recording.until(punch_in).recording {
+        if scheduler.wait_until_or_stopped(*at, &stop).is_none() {
+            conn_out.close();
+            return Ok(None);
+        }
+        let _ = conn_out.send(record_chunk);
+    }
+    if scheduler.wait_until_or_stopped(punch_in, &stop).is_none() {
+        conn_out.close();
+        return Ok(None);
+    }
+
+    let mut take = Recording::new();
+    let source = take.source(&in_port_name);
+    let take = Arc::new(Mutex::new(take));
+    let take_clone = take.clone();
+    let mut timeline = Timeline::new();
+
+    let source_name = in_port_name.clone();
+    let conn_in = backend.connect_input(
+        &in_port_name,
+        Box::new(move |stamp, message: &[u8]| {
+            take_clone.lock().unwrap().push((timeline.at(stamp), source, message.to_vec()));
+            if let Some(piano_event) = decoder.decode(message).transpose() {
+                handler(&source_name, piano_event);
+            }
+        })
+    )?;
+
+    let stopped = !matches!(stop.recv_timeout(punch_out - punch_in), Err(RecvTimeoutError::Timeout));
+
+    conn_in.close();
+    conn_out.close();
+    if stopped {
+        return Ok(None);
+    }
+
+    let take = take.lock().unwrap().clone();
+    Ok(Some(recording.punch(punch_in, punch_out, &take)))
+}
+
+/// Turns the raw messages of one input into `PianoEvent`s.
+///
+/// A CC 88 high-resolution velocity prefix is held on to until the note-on it belongs to arrives.
+/// Pedals keep their on/off state, so one resting between the thresholds doesn't flicker.
+pub struct Decoder {
+    profile: DeviceProfile,
+    velocity_low_bits: [Option<u8>; 16],
+    pedals: HashMap<(u8, PianoFunction), PedalSwitch>,
+}
+
+impl Decoder {
+    pub fn new(profile: DeviceProfile) -> Self {
+        Self { profile, velocity_low_bits: [None; 16], pedals: HashMap::new() }
+    }
+
+    /// Decodes one message, `None` for one that only prepares the next, like a velocity prefix.
+    pub fn decode(&mut self, message: &[u8]) -> Result<Option<PianoEvent>, PianoError> {
+        // System messages have no channel, of those only the clock and transport mean anything here
+        if message.first().is_some_and(|status| *status >= 0xf0) {
+            return TransportEvent::parse(message)
+                .map(|event| Some(PianoEvent::Transport(event)))
+                .ok_or_else(|| PianoError::UnhandledMessage(format!("{:?}", message)));
+        }
+        if message.len() < 3 {
+            return Err(PianoError::UnhandledMessage(format!("{:?}", message)));
+        }
+        let status = message[0] & 0xf0;
+        let channel = message[0] & 0x0f;
+
+        // Controllers aren't keys, so they don't go through `PianoKeyCode`
+        if status == (StateCode::FunctionBegin as u8) {
+            let change = ControlChange::new(message[1], message[2]);
+            if change.controller == Controller::HighResolutionVelocity {
+                self.velocity_low_bits[channel as usize] = Some(change.value);
+                return Ok(None);
+            }
+            let piano_event = PianoEvent::from_control_change(channel, change, &self.profile)?;
+            return Ok(Some(self.switch_pedal(channel, piano_event)));
+        }
+
+        let low_bits = match status == (StateCode::KeyPress as u8) {
+            true => self.velocity_low_bits[channel as usize].take(),
+            false => None,
+        };
+
+        let profile = &self.profile;
+        let state_code = StateCode::try_from(status)?;
+        let key_code = PianoKeyCode::try_from(message[1])?;
+        let piano_event = PianoEvent::new(key_code, state_code, Alpha(message[2]), profile)?;
+
+        match (piano_event, low_bits) {
+            (PianoEvent::KeyPress(key_code, _, velocity), Some(low_bits)) => {
+                let velocity = Velocity::with_low_bits(velocity.raw, low_bits);
+                let percent = Percent::with_curve(velocity, &profile.velocity_curve);
+                Ok(Some(PianoEvent::KeyPress(key_code, percent, velocity)))
+            }
+            (piano_event, _) => Ok(Some(piano_event)),
         }
     }
 
-    println!("Closing connection");
+    fn switch_pedal(&mut self, channel: u8, piano_event: PianoEvent) -> PianoEvent {
+        let thresholds = self.profile.pedal_thresholds;
+        let mut switch = |function, depth: Percent| {
+            self.pedals
+                .entry((channel, function))
+                .or_insert_with(|| PedalSwitch::new(thresholds))
+                .update(depth.0)
+        };
 
-    if record {
-        Ok(Some(recording))
-    } else {
-        Ok(None)
+        match piano_event {
+            PianoEvent::RightPedal(depth, _) => {
+                PianoEvent::RightPedal(depth, switch(PianoFunction::RightPedal, depth))
+            }
+            PianoEvent::MiddlePedal(depth, _) => {
+                PianoEvent::MiddlePedal(depth, switch(PianoFunction::MiddlePedal, depth))
+            }
+            PianoEvent::LeftPedal(depth, _) => {
+                PianoEvent::LeftPedal(depth, switch(PianoFunction::LeftPedal, depth))
+            }
+            piano_event => piano_event,
+        }
     }
 }
 
-pub fn play(recording: Recording) -> Result<(), Box<dyn Error>> {
-    let midi_out = MidiOutput::new("My Test Output")?;
+// Get the only input port there is, or one read from the console if there are several. The app's
+// own virtual input isn't picked this way, it is only listened to when asked for by name.
+fn select_input_port(backend: &Backend) -> Result<String, PianoError> {
+    let in_ports: Vec<String> = backend
+        .input_ports()?
+        .into_iter()
+        .filter(|name| name != VIRTUAL_INPUT)
+        .collect();
+    let in_port = match in_ports.len() {
+        0 => {
+            return Err(PianoError::NoInputPort);
+        }
+        1 => {
+            println!("Choosing the only available input port: {}", in_ports[0]);
+            &in_ports[0]
+        }
+        _ => {
+            println!("\nAvailable input ports:");
+            for (i, p) in in_ports.iter().enumerate() {
+                println!("{}: {}", i, p);
+            }
+            let index = prompt_port_index("Please select input port: ")?;
+            in_ports.get(index).ok_or_else(|| PianoError::InvalidPort(index.to_string()))?
+        }
+    };
 
-    // Get an output port (read from console if multiple are available)
-    let out_ports = midi_out.ports();
-    let out_port: &MidiOutputPort = match out_ports.len() {
+    Ok(in_port.clone())
+}
+
+// Get the only output port there is, or one read from the console if there are several, leaving
+// out the app's own virtual output like `select_input_port` does its input
+fn select_output_port(backend: &Backend) -> Result<String, PianoError> {
+    let out_ports: Vec<String> = backend
+        .output_ports()?
+        .into_iter()
+        .filter(|name| name != VIRTUAL_OUTPUT)
+        .collect();
+    let out_port = match out_ports.len() {
         0 => {
-            return Err("no output port found".into());
+            return Err(PianoError::NoOutputPort);
         }
         1 => {
-            println!(
-                "Choosing the only available output port: {}",
-                midi_out.port_name(&out_ports[0]).unwrap()
-            );
+            println!("Choosing the only available output port: {}", out_ports[0]);
             &out_ports[0]
         }
         _ => {
             println!("\nAvailable output ports:");
             for (i, p) in out_ports.iter().enumerate() {
-                println!("{}: {}", i, midi_out.port_name(p).unwrap());
+                println!("{}: {}", i, p);
             }
-            print!("Please select output port: ");
-            stdout().flush()?;
-            let mut input = String::new();
-            stdin().read_line(&mut input)?;
-            out_ports.get(input.trim().parse::<usize>()?).ok_or("invalid output port selected")?
+            let index = prompt_port_index("Please select output port: ")?;
+            out_ports.get(index).ok_or_else(|| PianoError::InvalidPort(index.to_string()))?
         }
     };
 
-    println!("\nOpening connection");
-    let mut conn_out = midi_out.connect(out_port, "midir-test")?;
-    println!("Connection open. Listen!");
-    {
-        // Define a new scope in which the closure `play_note` borrows conn_out, so it can be called easily
-        let mut play_note = |note: u8, duration: u64| {
-            // We're ignoring errors in here
-            let _ = conn_out.send(&[StateCode::KeyPress as u8, note, 60]);
-            sleep(Duration::from_millis(duration * 150));
-            let _ = conn_out.send(&[StateCode::KeyRelease as u8, note, 127]);
+    Ok(out_port.clone())
+}
+
+fn prompt_port_index(prompt: &str) -> Result<usize, PianoError> {
+    print!("{}", prompt);
+    stdout()
+        .flush()
+        .map_err(|e| PianoError::InvalidPort(e.to_string()))?;
+    let mut input = String::new();
+    stdin()
+        .read_line(&mut input)
+        .map_err(|e| PianoError::InvalidPort(e.to_string()))?;
+
+    input
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| PianoError::InvalidPort(input.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::clock::{ START, STOP };
+    use crate::mock_backend::MockBackend;
+    use crate::profile::ControlMapping;
+    use crate::routing::RouteFilter;
+    use crate::timecode::{ full_frame, FrameRate, Timecode };
+    use crate::transpose::Transpose;
+
+    const KEYS: &str = "Test Keys";
+    const SYNTH: &str = "Test Synth";
+
+    type Events = Arc<Mutex<Vec<Result<PianoEvent, PianoError>>>>;
+
+    // Listens with `options` until the messages scripted on the mock have been delivered, which
+    // they are as soon as the inputs connect
+    fn listen_to_script(
+        mock: &MockBackend,
+        options: ListenOptions
+    ) -> (Result<Option<Recording>, PianoError>, Events) {
+        let events: Events = Arc::default();
+        let handler_events = Arc::clone(&events);
+        let (stop, receiver) = bounded(1);
+        stop.send(()).unwrap();
+
+        let options = ListenOptions { backend: Backend::new(mock.clone()), ..options };
+        let outcome = listen(
+            move |_: &str, piano_event| handler_events.lock().unwrap().push(piano_event),
+            options,
+            receiver
+        );
+
+        (outcome, events)
+    }
+
+    fn keys() -> ListenOptions {
+        ListenOptions { ports: vec![KEYS.to_string()], ..Default::default() }
+    }
+
+    #[test]
+    fn decodes_keys_and_pedals() {
+        let mock = MockBackend::new(&[KEYS], &[]);
+        mock.script(KEYS, vec![(0, vec![0x90, 60, 100]), (10, vec![0xb0, 64, 127]), (20, vec![0x80, 60, 0])]);
+
+        let (outcome, events) = listen_to_script(&mock, keys());
+        assert!(outcome.unwrap().is_none());
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(
+            matches!(events[0], Ok(PianoEvent::KeyPress(PianoKeyCode::C4, _, Velocity { raw: 100, .. })))
+        );
+        assert!(matches!(events[1], Ok(PianoEvent::RightPedal(_, _))));
+        assert!(matches!(events[2], Ok(PianoEvent::KeyRelease(PianoKeyCode::C4))));
+    }
+
+    #[test]
+    fn records_messages_by_their_timestamps() {
+        let mock = MockBackend::new(&[KEYS], &[]);
+        mock.script(KEYS, vec![(1_000, vec![0x90, 60, 100]), (251_000, vec![0x80, 60, 0])]);
+
+        let (outcome, _) = listen_to_script(&mock, ListenOptions { record: true, ..keys() });
+        let recording = outcome.unwrap().expect("recording was requested");
+
+        assert_eq!(recording.sources, vec![KEYS.to_string()]);
+        let messages: Vec<&[u8]> = recording.recording
+            .iter()
+            .map(|(_, _, message)| message.as_slice())
+            .collect();
+        assert_eq!(messages, vec![&[0x90, 60, 100][..], &[0x80, 60, 0][..]]);
+        assert_eq!(recording.recording[1].0 - recording.recording[0].0, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn routes_with_channel_remap() {
+        let mock = MockBackend::new(&[KEYS], &[SYNTH]);
+        mock.script(KEYS, vec![(0, vec![0x90, 60, 100]), (10, vec![0xb0, 64, 127]), (20, vec![0x80, 60, 0])]);
+        let route = Route {
+            output: SYNTH.to_string(),
+            channel: Some(3),
+            filter: RouteFilter { controllers: false, ..Default::default() },
         };
 
-        for (time, record_chunk) in recording.recording {
-            sleep(time);
-            conn_out.send(&record_chunk);
+        let (outcome, _) = listen_to_script(&mock, ListenOptions { routes: vec![route], ..keys() });
+        outcome.unwrap();
+
+        assert_eq!(mock.sent(SYNTH), vec![vec![0x93, 60, 100], vec![0x83, 60, 0]]);
+    }
+
+    #[test]
+    fn transposes_routes_but_not_the_recording() {
+        let mock = MockBackend::new(&[KEYS], &[SYNTH]);
+        mock.script(KEYS, vec![(0, vec![0x90, 60, 100]), (10, vec![0x80, 60, 0])]);
+        let transpose = TransposeControl::default();
+        transpose.set(Transpose { semitones: 2, octaves: -1 });
+        let options = ListenOptions {
+            record: true,
+            routes: vec![Route { output: SYNTH.to_string(), channel: None, filter: RouteFilter::default() }],
+            transpose,
+            ..keys()
+        };
+
+        let (outcome, events) = listen_to_script(&mock, options);
+        let recording = outcome.unwrap().expect("recording was requested");
+
+        assert_eq!(mock.sent(SYNTH), vec![vec![0x90, 50, 100], vec![0x80, 50, 0]]);
+        assert_eq!(recording.recording[0].2, vec![0x90, 60, 100]);
+        assert!(matches!(events.lock().unwrap()[0], Ok(PianoEvent::KeyPress(key, _, _)) if key as u8 == 50));
+    }
+
+    #[test]
+    fn turns_clock_pulses_into_beats() {
+        let mock = MockBackend::new(&[KEYS], &[]);
+        let mut script = vec![(0, vec![0xfa])];
+        // Two beats at 120 bpm
+        let pulses = 0..(2 * PULSES_PER_BEAT as u64);
+        script.extend(pulses.map(|pulse| (1_000 + pulse * 20_833, vec![0xf8])));
+        mock.script(KEYS, script);
+
+        let (outcome, events) = listen_to_script(&mock, keys());
+        outcome.unwrap();
+
+        let events: Vec<TransportEvent> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|piano_event| match piano_event {
+                Ok(PianoEvent::Transport(event)) => *event,
+                other => panic!("expected only transport events, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(events, vec![TransportEvent::Start, TransportEvent::Beat(0), TransportEvent::Beat(1)]);
+    }
+
+    #[test]
+    fn starts_and_stops_repeatedly_without_leaking() {
+        let mock = MockBackend::new(&[KEYS], &[SYNTH]);
+        // Held by the handler, so it is only left alone once nothing can call the handler anymore
+        let events: Events = Arc::default();
+        for round in 0..20 {
+            let (stop, receiver) = bounded(1);
+            let handler_events = Arc::clone(&events);
+            // Everything that runs threads of its own: the arpeggiator's metronome and a clock master
+            let route = Route { output: SYNTH.to_string(), channel: None, filter: RouteFilter::default() };
+            let options = ListenOptions {
+                routes: vec![route],
+                arpeggiator: Some(ArpSettings::default()),
+                clock_output: Some(ClockOutput { output: SYNTH.to_string(), bpm: 240.0 }),
+                backend: Backend::new(mock.clone()),
+                ..keys()
+            };
+            let listener = thread::spawn(move || {
+                listen(
+                    move |_: &str, piano_event| handler_events.lock().unwrap().push(piano_event),
+                    options,
+                    receiver
+                )
+            });
+
+            while mock.receive(KEYS, 0, &[0x90, 60, 100]) == 0 {
+                sleep(Duration::from_millis(1));
+            }
+            sleep(Duration::from_millis(10));
+            // A dropped stop sender stops the listener as well
+            match round % 2 {
+                0 => stop.send(()).unwrap(),
+                _ => drop(stop),
+            }
+            listener.join().unwrap().unwrap();
+
+            assert_eq!(Arc::strong_count(&events), 1, "something still holds the handler");
+            assert_eq!(mock.receive(KEYS, 0, &[0x80, 60, 0]), 0, "an input is still connected");
         }
+
+        let sent = mock.sent(SYNTH);
+        assert_eq!(sent.iter().filter(|message| message[..] == [START]).count(), 20);
+        assert_eq!(sent.iter().filter(|message| message[..] == [STOP]).count(), 20);
+    }
+
+    #[test]
+    fn stops_when_the_handler_panics() {
+        let mock = MockBackend::new(&[KEYS], &[]);
+        mock.script(KEYS, vec![(0, vec![0x90, 60, 100])]);
+        let (_stop, receiver) = bounded(1);
+        let options = ListenOptions { backend: Backend::new(mock.clone()), ..keys() };
+
+        let outcome = listen(|_: &str, _| panic!("handler failed"), options, receiver);
+
+        assert!(matches!(outcome, Err(PianoError::Midi(_))));
+        assert_eq!(mock.receive(KEYS, 0, &[0x80, 60, 0]), 0);
+    }
+
+    #[test]
+    fn pedals_switch_at_the_profile_thresholds() {
+        let mut decoder = Decoder::new(Profiles::default().for_port(KEYS).clone());
+        let mut pressed = |depth| match decoder.decode(&[0xb0, 64, depth]) {
+            Ok(Some(PianoEvent::RightPedal(_, pressed))) => pressed,
+            piano_event => panic!("{:?}", piano_event),
+        };
+
+        // On from half way down, off again only below 40%
+        assert_eq!([60, 70, 55, 64].map(&mut pressed), [false, true, true, true]);
+        assert_eq!([50, 60, 80, 10].map(&mut pressed), [false, false, true, false]);
+    }
+
+    #[test]
+    fn absorbs_the_velocity_prefix_into_the_next_key_press() {
+        let mut decoder = Decoder::new(Profiles::default().for_port(KEYS).clone());
+
+        assert!(matches!(decoder.decode(&[0xb0, 88, 0x15]), Ok(None)));
+        let velocity = match decoder.decode(&[0x90, 60, 100]) {
+            Ok(Some(PianoEvent::KeyPress(_, _, velocity))) => velocity,
+            piano_event => panic!("{:?}", piano_event),
+        };
+        assert_eq!(velocity, Velocity::with_low_bits(100, 0x15));
+        // The prefix only counts for the very next key press
+        let velocity = match decoder.decode(&[0x90, 62, 100]) {
+            Ok(Some(PianoEvent::KeyPress(_, _, velocity))) => velocity,
+            piano_event => panic!("{:?}", piano_event),
+        };
+        assert_eq!(velocity.high_resolution, None);
+    }
+
+    #[test]
+    fn reports_messages_it_cannot_decode() {
+        let mut decoder = Decoder::new(Profiles::default().for_port(KEYS).clone());
+
+        // Pitch bend, a note off the piano, and a system message that isn't clock or transport
+        for message in [&[0xe0, 0, 64][..], &[0x90, 5, 100], &[0xfe]] {
+            assert!(matches!(decoder.decode(message), Err(PianoError::UnhandledMessage(_))), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn decodes_keys_and_pedals_on_any_channel() {
+        let mut decoder = Decoder::new(Profiles::default().for_port(KEYS).clone());
+
+        let key_press = decoder.decode(&[0x91, 60, 100]);
+        assert!(matches!(key_press, Ok(Some(PianoEvent::KeyPress(PianoKeyCode::C4, _, _)))));
+        assert!(matches!(decoder.decode(&[0x8f, 60, 0]), Ok(Some(PianoEvent::KeyRelease(PianoKeyCode::C4)))));
+        assert!(matches!(decoder.decode(&[0xb1, 64, 127]), Ok(Some(PianoEvent::RightPedal(_, _)))));
+        assert!(matches!(decoder.decode(&[0xb2, 91, 64]), Ok(Some(PianoEvent::SetAmbience(_)))));
+        assert!(matches!(decoder.decode(&[0xb5, 91, 64]), Ok(Some(PianoEvent::SetAmbience(_)))));
+    }
+
+    #[test]
+    fn prefers_a_mapping_for_the_very_channel() {
+        let mut profile = Profiles::default().for_port(KEYS).clone();
+        let ambience = ControlMapping { channel: Some(3), controller: 64, function: PianoFunction::Ambience };
+        profile.controls.push(ambience);
+        let mut decoder = Decoder::new(profile);
+
+        assert!(matches!(decoder.decode(&[0xb3, 64, 127]), Ok(Some(PianoEvent::SetAmbience(_)))));
+        assert!(matches!(decoder.decode(&[0xb4, 64, 127]), Ok(Some(PianoEvent::RightPedal(_, _)))));
+    }
+
+    #[test]
+    fn closes_inputs_when_stopped() {
+        let mock = MockBackend::new(&[KEYS], &[]);
+
+        let (outcome, _) = listen_to_script(&mock, keys());
+        outcome.unwrap();
+
+        assert_eq!(mock.receive(KEYS, 0, &[0x90, 60, 100]), 0);
+    }
+
+    #[test]
+    fn fails_on_an_unknown_port() {
+        let mock = MockBackend::new(&[KEYS], &[]);
+        let options = ListenOptions { ports: vec!["Missing".to_string()], ..Default::default() };
+
+        let (outcome, _) = listen_to_script(&mock, options);
+
+        assert!(matches!(outcome, Err(PianoError::InvalidPort(name)) if name == "Missing"));
+    }
+
+    #[test]
+    fn picks_the_only_input_port() {
+        let mock = MockBackend::new(&[KEYS, VIRTUAL_INPUT], &[]);
+        mock.script(KEYS, vec![(0, vec![0x90, 60, 100])]);
+
+        let (outcome, events) = listen_to_script(&mock, ListenOptions::default());
+        outcome.unwrap();
+
+        assert_eq!(events.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn samples_velocities_of_key_presses() {
+        let mock = MockBackend::new(&[KEYS], &[]);
+        mock.script(
+            KEYS,
+            vec![
+                (0, vec![0x90, 60, 100]),
+                (10, vec![0x80, 60, 0]),
+                // A note-on without velocity is a release
+                (20, vec![0x90, 62, 0]),
+                (30, vec![0x90, 64, 80]),
+            ]
+        );
+
+        let velocities = sample_velocities(&Backend::new(mock), KEYS, 2, Duration::from_secs(1)).unwrap();
+
+        assert_eq!(velocities, vec![100, 80]);
+    }
+
+    #[test]
+    fn plays_a_recording_in_order() {
+        let mock = MockBackend::new(&[], &[SYNTH]);
+        let recording = Recording::from(
+            vec![KEYS.to_string()],
+            vec![
+                (Duration::ZERO, 0, vec![0x90, 60, 100]),
+                (Duration::from_millis(5), 0, vec![0x90, 64, 100]),
+                (Duration::from_millis(10), 0, vec![0x80, 60, 0]),
+                (Duration::from_millis(10), 0, vec![0x80, 64, 0]),
+            ]
+        );
+        let options = PlayOptions {
+            output: Some(SYNTH.to_string()),
+            backend: Backend::new(mock.clone()),
+            ..Default::default()
+        };
+        let (_stop, receiver) = bounded(1);
+
+        play(recording.clone(), options, receiver).unwrap();
+
+        let expected: Vec<Vec<u8>> = recording.recording.into_iter().map(|(_, _, message)| message).collect();
+        assert_eq!(mock.sent(SYNTH), expected);
+    }
+
+    #[test]
+    fn plays_with_generated_timecode() {
+        let mock = MockBackend::new(&[], &[SYNTH]);
+        let recording = Recording::from(
+            vec![KEYS.to_string()],
+            vec![
+                (Duration::from_millis(10), 0, vec![0x90, 60, 100]),
+                (Duration::from_millis(50), 0, vec![0x80, 60, 0]),
+            ]
+        );
+        let start = Timecode { hours: 1, ..Default::default() };
+        let options = PlayOptions {
+            output: Some(SYNTH.to_string()),
+            timecode: Some(TimecodeSync::Generate { rate: FrameRate::Fps25, start }),
+            backend: Backend::new(mock.clone()),
+        };
+        let (_stop, receiver) = bounded(1);
+
+        play(recording, options, receiver).unwrap();
+
+        let sent = mock.sent(SYNTH);
+        assert_eq!(sent[0], full_frame(start, FrameRate::Fps25));
+        assert!(sent.iter().skip(1).any(|message| message[0] == 0xf1));
+        let notes: Vec<&Vec<u8>> = sent.iter().filter(|message| message[0] & 0xf0 != 0xf0).collect();
+        assert_eq!(notes, vec![&vec![0x90, 60, 100], &vec![0x80, 60, 0]]);
+    }
+
+    #[test]
+    fn fails_to_chase_an_unknown_port() {
+        let mock = MockBackend::new(&[], &[SYNTH]);
+        let recording = Recording::from(
+            vec![KEYS.to_string()],
+            vec![(Duration::ZERO, 0, vec![0x90, 60, 100])]
+        );
+        let options = PlayOptions {
+            output: Some(SYNTH.to_string()),
+            timecode: Some(TimecodeSync::Chase {
+                input: "Missing".to_string(),
+                rate: FrameRate::Fps30,
+                start: Timecode::default(),
+            }),
+            backend: Backend::new(mock.clone()),
+        };
+        let (_stop, receiver) = bounded(1);
+
+        assert!(matches!(play(recording, options, receiver), Err(PianoError::InvalidPort(_))));
+        assert!(mock.sent(SYNTH).is_empty());
     }
-    sleep(Duration::from_millis(150));
-    println!("\nClosing connection");
-    // This is optional, the connection would automatically be closed as soon as it goes out of scope
-    conn_out.close();
-    println!("Connection closed");
-    Ok(())
 }
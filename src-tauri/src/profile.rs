@@ -0,0 +1,209 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Mutex, PoisonError };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::error::PianoError;
+use crate::pedal::{ Damping, Thresholds };
+use crate::velocity::VelocityCurve;
+
+// Shipped with the app, the first one is the fallback for ports no profile claims. Keyboards that
+// use the standard controllers need no profile of their own. Only the generic profile ships for
+// now: there are no measured half-pedal thresholds or controller maps for particular models to
+// base brand profiles on, those are left to user profiles.
+const BUILTIN: [&str; 1] = [
+    include_str!("../profiles/generic.json"),
+];
+
+/// What a controller does on the piano.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PianoFunction {
+    RightPedal,
+    MiddlePedal,
+    LeftPedal,
+    Ambience,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlMapping {
+    /// Channel (0-15) the controller is sent on, any channel when left out
+    #[serde(default)]
+    pub channel: Option<u8>,
+    pub controller: u8,
+    pub function: PianoFunction,
+}
+
+/// How a particular keyboard reports its pedals and other functions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceProfile {
+    pub name: String,
+    /// The profile is used for ports whose name contains any of these (ignoring case)
+    pub ports: Vec<String>,
+    pub controls: Vec<ControlMapping>,
+    /// When the pedals count as pressed, for anything that needs them on or off
+    #[serde(default)]
+    pub pedal_thresholds: Thresholds,
+    /// How the dampers follow the sustain pedal, for working out how long notes ring
+    #[serde(default)]
+    pub damping: Damping,
+    /// Applied to the velocity of key presses
+    #[serde(default)]
+    pub velocity_curve: VelocityCurve,
+}
+
+impl DeviceProfile {
+    /// What `controller` does on `channel`, a mapping for that very channel winning over one for
+    /// any channel.
+    pub fn function(&self, channel: u8, controller: u8) -> Option<PianoFunction> {
+        self.controls
+            .iter()
+            .filter(|mapping| mapping.controller == controller)
+            .filter(|mapping| mapping.channel.is_none_or(|mapped| mapped == channel))
+            .max_by_key(|mapping| mapping.channel.is_some())
+            .map(|mapping| mapping.function)
+    }
+
+    // Length of the longest pattern found in `port`, if any
+    fn matching_length(&self, port: &str) -> Option<usize> {
+        let port = port.to_lowercase();
+        self.ports
+            .iter()
+            .filter(|pattern| port.contains(&pattern.to_lowercase()))
+            .map(|pattern| pattern.len())
+            .max()
+    }
+}
+
+/// The known device profiles, the built-in ones followed by any loaded from disk.
+#[derive(Debug, Clone)]
+pub struct Profiles(Vec<DeviceProfile>);
+
+impl Profiles {
+    pub fn builtin() -> Self {
+        Self(
+            BUILTIN.iter()
+                .map(|json| serde_json::from_str(json).expect("Invalid built-in device profile"))
+                .collect()
+        )
+    }
+
+    /// The built-in profiles plus every `*.json` profile in `dir`. A profile named like a built-in
+    /// one replaces it, and files that can't be read are skipped. Other formats (TOML) aren't
+    /// read.
+    pub fn load(dir: &Path) -> Self {
+        let mut profiles = Self::builtin();
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => {
+                return profiles;
+            }
+        };
+
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+
+            match read(&path) {
+                Ok(profile) => profiles.insert(profile),
+                Err(e) => { println!("Skipping device profile {:?}: {}", path, e) }
+            }
+        }
+
+        profiles
+    }
+
+    pub fn insert(&mut self, profile: DeviceProfile) {
+        match self.0.iter_mut().find(|known| known.name == profile.name) {
+            Some(known) => {
+                *known = profile;
+            }
+            None => self.0.push(profile),
+        }
+    }
+
+    pub fn profiles(&self) -> &[DeviceProfile] {
+        &self.0
+    }
+
+    /// The profile for the port called `port`, the generic one if no profile claims it. When
+    /// several do, the most specific pattern wins, and among equals the profile loaded last.
+    pub fn for_port(&self, port: &str) -> &DeviceProfile {
+        self.0
+            .iter()
+            .skip(1)
+            .filter_map(|profile| profile.matching_length(port).map(|length| (length, profile)))
+            .max_by_key(|(length, _)| *length)
+            .map(|(_, profile)| profile)
+            .unwrap_or(&self.0[0])
+    }
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+/// The profiles in use, along with the directory customized profiles are saved to.
+pub struct ProfileStore {
+    dir: Option<PathBuf>,
+    profiles: Mutex<Profiles>,
+}
+
+impl ProfileStore {
+    pub fn load(dir: Option<PathBuf>) -> Self {
+        let profiles = match &dir {
+            Some(dir) => Profiles::load(dir),
+            None => Profiles::builtin(),
+        };
+        Self { dir, profiles: Mutex::new(profiles) }
+    }
+
+    pub fn profiles(&self) -> Profiles {
+        self.profiles.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Changes the profile used for `port` and saves it.
+    ///
+    /// A profile shared with other keyboards is first copied into one for just this port, so the
+    /// change doesn't affect them.
+    pub fn customize<F>(&self, port: &str, change: F) -> Result<DeviceProfile, PianoError>
+        where F: FnOnce(&mut DeviceProfile)
+    {
+        let mut profiles = self.profiles.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut profile = profiles.for_port(port).clone();
+        if profile.ports != [port] {
+            profile.name = port.to_string();
+            profile.ports = vec![port.to_string()];
+        }
+        change(&mut profile);
+
+        if let Some(dir) = &self.dir {
+            write(dir, &profile)?;
+        }
+        profiles.insert(profile.clone());
+
+        Ok(profile)
+    }
+}
+
+fn write(dir: &Path, profile: &DeviceProfile) -> Result<(), PianoError> {
+    let file_name: String = profile.name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    let json = serde_json::to_string_pretty(profile).map_err(|e| PianoError::Profile(e.to_string()))?;
+
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(dir.join(format!("{}.json", file_name)), json))
+        .map_err(|e| PianoError::Profile(e.to_string()))
+}
+
+fn read(path: &Path) -> Result<DeviceProfile, PianoError> {
+    let json = fs::read_to_string(path).map_err(|e| PianoError::Profile(e.to_string()))?;
+    serde_json::from_str(&json).map_err(|e| PianoError::Profile(e.to_string()))
+}
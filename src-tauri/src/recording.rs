@@ -0,0 +1,433 @@
+use std::collections::{ HashMap, HashSet };
+use std::time::{ Duration, Instant };
+
+use crate::piano_listen::{ ControlChange, Controller, StateCode };
+use crate::transpose::{ Transpose, Transposer };
+
+/// Index of the device a chunk came from in `Recording::sources`.
+pub type SourceId = u8;
+
+#[derive(Debug, Clone)]
+pub struct Recording {
+    /// Names of the devices the chunks came from, indexed by `SourceId`
+    pub sources: Vec<String>,
+    /// Raw MIDI messages, each stamped with its time since the start of the recording and its
+    /// source, in time order
+    pub recording: Vec<(Duration, SourceId, Vec<u8>)>,
+}
+
+impl Recording {
+    pub fn new() -> Self {
+        Self { sources: Vec::new(), recording: Vec::new() }
+    }
+
+    pub fn from(sources: Vec<String>, recording: Vec<(Duration, SourceId, Vec<u8>)>) -> Self {
+        Self { sources, recording }
+    }
+
+    /// The id of the source called `name`, which is added if it isn't known yet.
+    pub fn source(&mut self, name: &str) -> SourceId {
+        match self.sources.iter().position(|source| source == name) {
+            Some(id) => id as SourceId,
+            None => {
+                self.sources.push(name.to_string());
+                (self.sources.len() - 1) as SourceId
+            }
+        }
+    }
+
+    pub fn source_name(&self, id: SourceId) -> Option<&str> {
+        self.sources.get(id as usize).map(String::as_str)
+    }
+
+    /// Adds a chunk, keeping the chunks in time order.
+    ///
+    /// Chunks from different devices can arrive slightly out of order, so a chunk earlier than the
+    /// last one is moved into place (after any chunks at the same time).
+    pub fn push(&mut self, chunk: (Duration, SourceId, Vec<u8>)) {
+        match self.recording.last() {
+            Some((last, _, _)) if *last > chunk.0 => {
+                let index = self.recording.partition_point(|(at, _, _)| *at <= chunk.0);
+                self.recording.insert(index, chunk);
+            }
+            _ => self.recording.push(chunk),
+        }
+    }
+
+    /// Everything that happens before `time`, with notes still held and pedals still down at that
+    /// point released.
+    pub fn until(&self, time: Duration) -> Recording {
+        let mut chunks = Vec::new();
+        let mut held = HeldNotes::new();
+        let mut pedals = HeldPedals::new();
+        for (at, source, message) in self.recording.iter().filter(|(at, _, _)| *at < time) {
+            held.track(*source, message);
+            pedals.track(*source, message);
+            chunks.push((*at, *source, message.clone()));
+        }
+        chunks.extend(held.release_all(time));
+        chunks.extend(pedals.change_to(&HeldPedals::new(), time));
+
+        Self::from(self.sources.clone(), chunks)
+    }
+
+    /// Replaces everything between `punch_in` and `punch_out` with `take`.
+    ///
+    /// `take` is timed relative to `punch_in`, anything it contains past `punch_out` is dropped.
+    /// Notes still sounding when either boundary is crossed get a release at the boundary, and
+    /// releases after `punch_out` belonging to notes that were cut away are removed. Pedals are let
+    /// up at `punch_in`, and at `punch_out` go back to where the recording had them.
+    /// The sources of `take` are merged into those of the recording by name.
+    pub fn punch(&self, punch_in: Duration, punch_out: Duration, take: &Recording) -> Recording {
+        let mut punched = self.until(punch_in);
+
+        let mut held = HeldNotes::new();
+        let mut pedals = HeldPedals::new();
+        for (at, source, message) in &take.recording {
+            let at = punch_in + *at;
+            if at >= punch_out {
+                break;
+            }
+            let source = punched.source(take.source_name(*source).unwrap_or_default());
+            held.track(source, message);
+            pedals.track(source, message);
+            punched.recording.push((at, source, message.clone()));
+        }
+        punched.recording.extend(held.release_all(punch_out));
+
+        let mut orphaned = HeldNotes::new();
+        let mut original_pedals = HeldPedals::new();
+        for (_, source, message) in self.recording.iter().filter(|(at, _, _)| *at < punch_out) {
+            orphaned.track(*source, message);
+            original_pedals.track(*source, message);
+        }
+        punched.recording.extend(pedals.change_to(&original_pedals, punch_out));
+        for (at, source, message) in self.recording.iter().filter(|(at, _, _)| *at >= punch_out) {
+            if is_note_on(message) {
+                orphaned.forget(*source, message);
+            } else if is_note_off(message) && orphaned.forget(*source, message) {
+                continue;
+            }
+            punched.recording.push((*at, *source, message.clone()));
+        }
+
+        punched
+    }
+
+    /// A copy of the recording with every note moved by `transpose`, leaving the recording itself
+    /// as it was played.
+    pub fn transposed(&self, transpose: Transpose) -> Recording {
+        let shift = transpose.shift();
+        if shift == 0 {
+            return self.clone();
+        }
+
+        let mut transposers: HashMap<SourceId, Transposer> = HashMap::new();
+        let chunks = self.recording
+            .iter()
+            .filter_map(|(at, source, message)| {
+                transposers
+                    .entry(*source)
+                    .or_default()
+                    .apply(message, shift)
+                    .map(|message| (*at, *source, message))
+            })
+            .collect();
+
+        Recording::from(self.sources.clone(), chunks)
+    }
+}
+
+impl Default for Recording {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_note_on(message: &[u8]) -> bool {
+    message.len() >= 3 && (message[0] & 0xf0) == (StateCode::KeyPress as u8) && message[2] > 0
+}
+
+fn is_note_off(message: &[u8]) -> bool {
+    message.len() >= 3 &&
+        ((message[0] & 0xf0) == (StateCode::KeyRelease as u8) ||
+            ((message[0] & 0xf0) == (StateCode::KeyPress as u8) && message[2] == 0))
+}
+
+/// Maps the driver timestamps of incoming messages onto the time since a recording started.
+///
+/// The driver clock has an unspecified origin, so it is anchored against the wall clock once, at
+/// the first message, and every later time is taken purely from the driver timestamps. That keeps
+/// scheduling and lock contention in the input callback out of the recorded timing.
+#[derive(Debug)]
+pub struct Timeline {
+    started: Instant,
+    anchor: Option<(u64, Duration)>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    /// A timeline counting from `started`, so several inputs can share one.
+    pub fn starting_at(started: Instant) -> Self {
+        Self { started, anchor: None }
+    }
+
+    /// Converts a driver timestamp in microseconds to the time since the timeline started.
+    pub fn at(&mut self, stamp: u64) -> Duration {
+        match self.anchor {
+            Some((anchor_stamp, anchor_at)) if stamp >= anchor_stamp => {
+                anchor_at + Duration::from_micros(stamp - anchor_stamp)
+            }
+            // First message, or the driver clock went backwards
+            _ => {
+                let at = self.started.elapsed();
+                self.anchor = Some((stamp, at));
+                at
+            }
+        }
+    }
+
+    /// Anchors again at the next message, for when the driver timestamps start over (e.g. after
+    /// reconnecting to a port).
+    pub fn reanchor(&mut self) {
+        self.anchor = None;
+    }
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keeps track of which (source, channel, note) triples are sounding.
+struct HeldNotes(HashSet<(SourceId, u8, u8)>);
+
+impl HeldNotes {
+    fn new() -> Self {
+        Self(HashSet::new())
+    }
+
+    fn track(&mut self, source: SourceId, message: &[u8]) {
+        if is_note_on(message) {
+            self.0.insert((source, message[0] & 0x0f, message[1]));
+        } else if is_note_off(message) {
+            self.0.remove(&(source, message[0] & 0x0f, message[1]));
+        }
+    }
+
+    fn forget(&mut self, source: SourceId, message: &[u8]) -> bool {
+        self.0.remove(&(source, message[0] & 0x0f, message[1]))
+    }
+
+    fn release_all(self, at: Duration) -> Vec<(Duration, SourceId, Vec<u8>)> {
+        let mut notes: Vec<_> = self.0.into_iter().collect();
+        notes.sort();
+        notes
+            .into_iter()
+            .map(|(source, channel, note)| {
+                (at, source, vec![(StateCode::KeyRelease as u8) | channel, note, 0])
+            })
+            .collect()
+    }
+}
+
+/// Keeps track of where the pedals (sustain, sostenuto and soft) of every (source, channel) are.
+struct HeldPedals(HashMap<(SourceId, u8, u8), u8>);
+
+impl HeldPedals {
+    fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    fn track(&mut self, source: SourceId, message: &[u8]) {
+        if message.len() < 3 || (message[0] & 0xf0) != (StateCode::FunctionBegin as u8) {
+            return;
+        }
+        let change = ControlChange::new(message[1], message[2]);
+        if matches!(change.controller, Controller::Sustain | Controller::Sostenuto | Controller::Soft) {
+            self.0.insert((source, message[0] & 0x0f, message[1]), change.value);
+        }
+    }
+
+    // The control changes at `at` that move the pedals from here to where `target` has them, a
+    // pedal neither of them has seen being up
+    fn change_to(&self, target: &HeldPedals, at: Duration) -> Vec<(Duration, SourceId, Vec<u8>)> {
+        let mut pedals: Vec<_> = self.0.keys().chain(target.0.keys()).copied().collect();
+        pedals.sort();
+        pedals.dedup();
+        pedals
+            .into_iter()
+            .filter_map(|(source, channel, controller)| {
+                let value = target.value(source, channel, controller);
+                (self.value(source, channel, controller) != value).then(|| {
+                    (at, source, vec![(StateCode::FunctionBegin as u8) | channel, controller, value])
+                })
+            })
+            .collect()
+    }
+
+    fn value(&self, source: SourceId, channel: u8, controller: u8) -> u8 {
+        self.0.get(&(source, channel, controller)).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn piano(chunks: Vec<(u64, Vec<u8>)>) -> Recording {
+        let chunks = chunks.into_iter().map(|(at, message)| (ms(at), 0, message)).collect();
+        Recording::from(vec!["Piano".to_string()], chunks)
+    }
+
+    fn messages_at(recording: &Recording, at: u64) -> Vec<Vec<u8>> {
+        recording.recording
+            .iter()
+            .filter(|(chunk_at, _, _)| *chunk_at == ms(at))
+            .map(|(_, _, message)| message.clone())
+            .collect()
+    }
+
+    // Driver timestamps of a message every 997 µs (so they don't line up with whole
+    // milliseconds) for `hours`, starting at `from`
+    fn stamps(from: u64, hours: u64) -> impl Iterator<Item = u64> {
+        (0..(hours * 3_600_000_000 / 997)).map(move |index| from + index * 997)
+    }
+
+    #[test]
+    fn timeline_does_not_drift_over_hours() {
+        let mut timeline = Timeline::new();
+        let first = timeline.at(1_234_567);
+
+        for stamp in stamps(1_234_567, 3) {
+            assert_eq!(timeline.at(stamp) - first, Duration::from_micros(stamp - 1_234_567));
+        }
+    }
+
+    #[test]
+    fn timeline_does_not_drift_after_reanchoring() {
+        let mut timeline = Timeline::new();
+        let first = timeline.at(0);
+        let before: Vec<Duration> = stamps(0, 2).map(|stamp| timeline.at(stamp)).collect();
+        assert_eq!(*before.last().unwrap() - first, Duration::from_micros(stamps(0, 2).last().unwrap()));
+
+        // The driver clock starts over, as it does after a reconnect
+        timeline.reanchor();
+        let anchor = timeline.at(500);
+        assert!(anchor >= first);
+        for stamp in stamps(500, 2) {
+            assert_eq!(timeline.at(stamp) - anchor, Duration::from_micros(stamp - 500));
+        }
+    }
+
+    #[test]
+    fn timeline_reanchors_when_the_driver_clock_goes_back() {
+        let mut timeline = Timeline::new();
+        let first = timeline.at(10_000_000);
+        assert_eq!(timeline.at(10_500_000) - first, Duration::from_millis(500));
+
+        let anchor = timeline.at(20);
+        assert!(anchor < first + Duration::from_millis(500));
+        assert_eq!(timeline.at(1_020) - anchor, Duration::from_millis(1));
+    }
+
+    fn chunks(recording: &Recording) -> Vec<(u64, SourceId, Vec<u8>)> {
+        recording.recording
+            .iter()
+            .map(|(at, source, message)| (at.as_millis() as u64, *source, message.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn until_releases_the_notes_still_held() {
+        let recording = piano(vec![
+            (0, vec![0x90, 60, 100]),
+            (100, vec![0x90, 64, 100]),
+            (200, vec![0x90, 64, 0]),
+            (300, vec![0x90, 67, 100]),
+        ]);
+
+        assert_eq!(
+            chunks(&recording.until(ms(250))),
+            vec![
+                (0, 0, vec![0x90, 60, 100]),
+                (100, 0, vec![0x90, 64, 100]),
+                (200, 0, vec![0x90, 64, 0]),
+                (250, 0, vec![0x80, 60, 0])
+            ]
+        );
+        assert!(recording.until(ms(0)).recording.is_empty());
+    }
+
+    #[test]
+    fn punch_replaces_the_range_with_the_take() {
+        let recording = piano(vec![
+            (100, vec![0x90, 60, 100]),
+            (600, vec![0x80, 60, 0]),
+            (700, vec![0x90, 62, 100]),
+            (1200, vec![0x80, 62, 0]),
+            (1500, vec![0x90, 64, 100]),
+            (1700, vec![0x80, 64, 0]),
+        ]);
+        let mut take = Recording::new();
+        let keys = take.source("Keys");
+        take.push((ms(100), keys, vec![0x90, 65, 100]));
+        take.push((ms(200), keys, vec![0x90, 67, 90]));
+        take.push((ms(300), keys, vec![0x80, 67, 0]));
+        // Past punch-out, so left out
+        take.push((ms(800), keys, vec![0x90, 69, 100]));
+
+        let punched = recording.punch(ms(300), ms(1000), &take);
+
+        assert_eq!(punched.sources, ["Piano", "Keys"]);
+        assert_eq!(
+            chunks(&punched),
+            vec![
+                (100, 0, vec![0x90, 60, 100]),
+                (300, 0, vec![0x80, 60, 0]),
+                (400, 1, vec![0x90, 65, 100]),
+                (500, 1, vec![0x90, 67, 90]),
+                (600, 1, vec![0x80, 67, 0]),
+                (1000, 1, vec![0x80, 65, 0]),
+                // The release of the note cut away at 700 is gone
+                (1500, 0, vec![0x90, 64, 100]),
+                (1700, 0, vec![0x80, 64, 0])
+            ]
+        );
+    }
+
+    #[test]
+    fn punch_lets_the_pedal_up_when_its_release_is_cut() {
+        let recording = piano(vec![(0, vec![0xb0, 64, 127]), (500, vec![0xb0, 64, 0])]);
+
+        let punched = recording.punch(ms(200), ms(1000), &Recording::new());
+
+        assert_eq!(messages_at(&punched, 200), vec![vec![0xb0, 64, 0]]);
+        let sustain: Vec<u8> = punched.recording.iter().map(|(_, _, message)| message[2]).collect();
+        assert_eq!(sustain.last(), Some(&0));
+    }
+
+    #[test]
+    fn punch_puts_the_pedals_back_at_punch_out() {
+        let recording = piano(
+            vec![(100, vec![0xb0, 64, 127]), (300, vec![0xb1, 67, 90]), (2000, vec![0xb0, 64, 0])]
+        );
+        let take = piano(vec![(100, vec![0xb0, 64, 0]), (200, vec![0xb0, 66, 127])]);
+
+        let punched = recording.punch(ms(200), ms(1000), &take);
+
+        assert_eq!(
+            messages_at(&punched, 1000),
+            vec![vec![0xb0, 64, 127], vec![0xb0, 66, 0], vec![0xb1, 67, 90]]
+        );
+        assert_eq!(messages_at(&punched, 2000), vec![vec![0xb0, 64, 0]]);
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{ Deserialize, Serialize };
+
+use crate::backend::{ Backend, OutputConnection };
+use crate::error::PianoError;
+use crate::piano_listen::StateCode;
+
+/// Which messages a route lets through. Empty lists let everything through.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RouteFilter {
+    /// Names of the input ports to take messages from
+    pub sources: Vec<String>,
+    /// Channels (0 to 15) to take channel messages from
+    pub channels: Vec<u8>,
+    pub notes: bool,
+    pub controllers: bool,
+    /// Everything that is neither a note nor a controller (pitch bend, system messages, ...)
+    pub other: bool,
+}
+
+impl Default for RouteFilter {
+    fn default() -> Self {
+        Self { sources: Vec::new(), channels: Vec::new(), notes: true, controllers: true, other: true }
+    }
+}
+
+impl RouteFilter {
+    pub fn passes(&self, source: &str, message: &[u8]) -> bool {
+        if message.is_empty() {
+            return false;
+        }
+        if !self.sources.is_empty() && !self.sources.iter().any(|name| name == source) {
+            return false;
+        }
+        let channel = message[0] & 0x0f;
+        if is_channel_message(message) && !self.channels.is_empty() && !self.channels.contains(&channel) {
+            return false;
+        }
+
+        let status = message[0] & 0xf0;
+        if status == (StateCode::KeyPress as u8) || status == (StateCode::KeyRelease as u8) {
+            self.notes
+        } else if status == (StateCode::FunctionBegin as u8) {
+            self.controllers
+        } else {
+            self.other
+        }
+    }
+}
+
+/// Forwards incoming messages to an output port.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Route {
+    /// Name of the output port
+    pub output: String,
+    /// Channel (0 to 15) to move channel messages to, if any
+    #[serde(default)]
+    pub channel: Option<u8>,
+    #[serde(default)]
+    pub filter: RouteFilter,
+}
+
+impl Route {
+    /// The message as it leaves through this route, `None` if the route doesn't take it.
+    pub fn apply(&self, source: &str, message: &[u8]) -> Option<Vec<u8>> {
+        if !self.filter.passes(source, message) {
+            return None;
+        }
+
+        let mut message = message.to_vec();
+        if let Some(channel) = self.channel {
+            if is_channel_message(&message) {
+                message[0] = (message[0] & 0xf0) | (channel & 0x0f);
+            }
+        }
+
+        Some(message)
+    }
+}
+
+/// Sends incoming messages on along their routes as they arrive.
+pub struct Router {
+    routes: Vec<(Route, Box<dyn OutputConnection>)>,
+}
+
+impl Router {
+    /// Opens the output port of every route.
+    pub fn connect(backend: &Backend, routes: Vec<Route>) -> Result<Self, PianoError> {
+        let mut connected = Vec::new();
+        for route in routes {
+            let connection = backend.connect_output(&route.output)?;
+            connected.push((route, connection));
+        }
+
+        Ok(Self { routes: connected })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn send(&mut self, source: &str, message: &[u8]) {
+        for (route, connection) in &mut self.routes {
+            if let Some(message) = route.apply(source, message) {
+                // A failing output mustn't hold up the input or the other routes
+                if let Err(e) = connection.send(&message) {
+                    println!("Failed to route to '{}': {}", route.output, e);
+                }
+            }
+        }
+    }
+
+    pub fn close(self) {
+        for (_, connection) in self.routes {
+            connection.close();
+        }
+    }
+}
+
+pub fn is_channel_message(message: &[u8]) -> bool {
+    (0x80..0xf0).contains(&message[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend::MockBackend;
+
+    const KEYS: &str = "Keys";
+    const PADS: &str = "Pads";
+    const SYNTH: &str = "Synth";
+    const DRUMS: &str = "Drums";
+
+    fn route(output: &str, filter: RouteFilter) -> Route {
+        Route { output: output.to_string(), channel: None, filter }
+    }
+
+    #[test]
+    fn filters_by_channel() {
+        let filter = RouteFilter { channels: vec![0, 9], ..Default::default() };
+
+        assert!(filter.passes(KEYS, &[0x90, 60, 100]));
+        assert!(filter.passes(KEYS, &[0xb9, 64, 127]));
+        assert!(!filter.passes(KEYS, &[0x91, 60, 100]));
+        // System messages have no channel to filter by
+        assert!(filter.passes(KEYS, &[0xf8]));
+    }
+
+    #[test]
+    fn filters_by_message_type() {
+        let notes = RouteFilter { controllers: false, other: false, ..Default::default() };
+        let controllers = RouteFilter { notes: false, other: false, ..Default::default() };
+        let other = RouteFilter { notes: false, controllers: false, ..Default::default() };
+        let messages: [&[u8]; 5] = [
+            &[0x90, 60, 100],
+            &[0x80, 60, 0],
+            &[0xb0, 64, 127],
+            &[0xe0, 0, 64],
+            &[0xf8],
+        ];
+
+        let passing = |filter: &RouteFilter| -> Vec<bool> {
+            messages.iter().map(|message| filter.passes(KEYS, message)).collect()
+        };
+
+        assert_eq!(passing(&notes), vec![true, true, false, false, false]);
+        assert_eq!(passing(&controllers), vec![false, false, true, false, false]);
+        assert_eq!(passing(&other), vec![false, false, false, true, true]);
+    }
+
+    #[test]
+    fn filters_by_source() {
+        let filter = RouteFilter { sources: vec![PADS.to_string()], ..Default::default() };
+
+        assert!(filter.passes(PADS, &[0x99, 36, 100]));
+        assert!(!filter.passes(KEYS, &[0x99, 36, 100]));
+    }
+
+    #[test]
+    fn moves_only_channel_messages_to_the_route_channel() {
+        let route = Route { channel: Some(3), ..route(SYNTH, RouteFilter::default()) };
+
+        assert_eq!(route.apply(KEYS, &[0x90, 60, 100]), Some(vec![0x93, 60, 100]));
+        assert_eq!(route.apply(KEYS, &[0xf8]), Some(vec![0xf8]));
+    }
+
+    #[test]
+    fn routes_one_input_to_several_outputs() {
+        let mock = MockBackend::new(&[KEYS], &[SYNTH, DRUMS]);
+        let drum_notes = RouteFilter { controllers: false, ..Default::default() };
+        let routes = vec![
+            route(SYNTH, RouteFilter::default()),
+            Route { channel: Some(9), ..route(DRUMS, drum_notes) },
+        ];
+        let mut router = Router::connect(&Backend::new(mock.clone()), routes).unwrap();
+
+        router.send(KEYS, &[0x90, 36, 100]);
+        router.send(KEYS, &[0xb0, 64, 127]);
+        router.close();
+
+        assert_eq!(mock.sent(SYNTH), vec![vec![0x90, 36, 100], vec![0xb0, 64, 127]]);
+        assert_eq!(mock.sent(DRUMS), vec![vec![0x99, 36, 100]]);
+    }
+}
@@ -0,0 +1,173 @@
+use std::sync::{ Arc, Mutex, PoisonError };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::piano_listen::PianoEvent;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scale {
+    #[default]
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    MelodicMinor,
+    Dorian,
+    Mixolydian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+}
+
+impl Scale {
+    /// The notes of the scale, in semitones above its root.
+    pub fn intervals(self) -> &'static [u8] {
+        match self {
+            Self::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Self::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            Self::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Self::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            Self::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Self::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Self::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Self::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Self::Blues => &[0, 3, 5, 6, 7, 10],
+        }
+    }
+}
+
+/// What happens to notes outside the scale.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScaleMode {
+    #[default]
+    Off,
+    /// Played as the nearest note of the scale, the lower one when two are as near
+    Snap,
+    /// Not played at all
+    Mute,
+    /// Played as they are, but flagged as out of scale on their way to the UI
+    Highlight,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScaleLock {
+    /// Pitch class of the root, 0 for C up to 11 for B
+    pub root: u8,
+    pub scale: Scale,
+    pub mode: ScaleMode,
+}
+
+impl ScaleLock {
+    /// Whether the MIDI note `note` is in the scale.
+    pub fn contains(&self, note: u8) -> bool {
+        let degree = (note + 12 - self.root % 12) % 12;
+        self.scale.intervals().contains(&degree)
+    }
+
+    /// The note `note` is played as, `None` if it is muted.
+    pub fn map(&self, note: u8) -> Option<u8> {
+        match self.mode {
+            ScaleMode::Off | ScaleMode::Highlight => Some(note),
+            _ if self.contains(note) => Some(note),
+            ScaleMode::Mute => None,
+            // Every scale has a note within a tritone
+            ScaleMode::Snap => {
+                (1..=6u8)
+                    .flat_map(|distance| [note.checked_sub(distance), note.checked_add(distance)])
+                    .flatten()
+                    .find(|candidate| *candidate <= 0x7f && self.contains(*candidate))
+            }
+        }
+    }
+
+    /// Whether `piano_event` is a key press to flag as out of scale.
+    pub fn flags(&self, piano_event: &PianoEvent) -> bool {
+        match piano_event {
+            PianoEvent::KeyPress(key, _, _) => {
+                self.mode == ScaleMode::Highlight && !self.contains(*key as u8)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The scale lock applied to live input, shared between the commands changing it and the
+/// listeners reading it. A change takes effect at the next key press.
+#[derive(Debug, Clone, Default)]
+pub struct ScaleControl(Arc<Mutex<ScaleLock>>);
+
+impl ScaleControl {
+    pub fn get(&self) -> ScaleLock {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn set(&self, scale_lock: ScaleLock) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = scale_lock;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(root: u8, scale: Scale, mode: ScaleMode) -> ScaleLock {
+        ScaleLock { root, scale, mode }
+    }
+
+    #[test]
+    fn snaps_to_the_nearest_note_of_the_scale() {
+        let c_major = lock(0, Scale::Major, ScaleMode::Snap);
+        assert_eq!(c_major.map(60), Some(60));
+        // F and G are as near to F#, the lower one wins
+        assert_eq!(c_major.map(66), Some(65));
+        assert_eq!(c_major.map(61), Some(60));
+
+        let pentatonic = lock(0, Scale::MajorPentatonic, ScaleMode::Snap);
+        assert_eq!(pentatonic.map(65), Some(64));
+        assert_eq!(pentatonic.map(71), Some(72));
+
+        // B and C# are as near to C in D major
+        assert_eq!(lock(2, Scale::Major, ScaleMode::Snap).map(60), Some(59));
+    }
+
+    #[test]
+    fn snapping_stays_in_the_midi_range() {
+        // G isn't in C# major, F# is
+        assert_eq!(lock(1, Scale::Major, ScaleMode::Snap).map(127), Some(126));
+        // C isn't in D major and there is nothing below it, C# is
+        assert_eq!(lock(2, Scale::Major, ScaleMode::Snap).map(0), Some(1));
+    }
+
+    #[test]
+    fn mutes_or_passes_notes_outside_the_scale() {
+        assert_eq!(lock(0, Scale::Major, ScaleMode::Mute).map(61), None);
+        assert_eq!(lock(0, Scale::Major, ScaleMode::Mute).map(62), Some(62));
+        assert_eq!(lock(0, Scale::Major, ScaleMode::Highlight).map(61), Some(61));
+        assert_eq!(lock(0, Scale::Major, ScaleMode::Off).map(61), Some(61));
+    }
+
+    #[test]
+    fn every_note_is_in_reach_of_every_scale() {
+        let scales = [
+            Scale::Major,
+            Scale::NaturalMinor,
+            Scale::HarmonicMinor,
+            Scale::MelodicMinor,
+            Scale::Dorian,
+            Scale::Mixolydian,
+            Scale::MajorPentatonic,
+            Scale::MinorPentatonic,
+            Scale::Blues,
+        ];
+        for scale in scales {
+            for root in 0..12 {
+                let scale_lock = lock(root, scale, ScaleMode::Snap);
+                for note in 0..=127 {
+                    let snapped = scale_lock.map(note).unwrap();
+                    assert!(scale_lock.contains(snapped), "{} in {:?}", note, scale);
+                    assert!(snapped.abs_diff(note) <= 6, "{} in {:?}", note, scale);
+                }
+            }
+        }
+    }
+}
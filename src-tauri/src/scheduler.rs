@@ -0,0 +1,202 @@
+use std::hint::spin_loop;
+use std::thread::sleep;
+use std::time::{ Duration, Instant };
+
+use crossbeam_channel::{ Receiver, TryRecvError };
+
+// Longest single sleep while waiting for a stop request, bounds how long stopping can take
+const STOP_POLL: Duration = Duration::from_millis(20);
+
+pub trait Clock {
+    /// Time since some fixed point in the past, never going backwards.
+    fn now(&self) -> Duration;
+
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    origin: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        Self { origin: Instant::now() }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        sleep(duration)
+    }
+}
+
+/// Waits for absolute points in time, measured from when the scheduler was created.
+///
+/// Targets are absolute rather than relative to the previous wait, so any overshoot is absorbed
+/// by the next wait instead of accumulating. The last `spin` before each target is spent
+/// spin-waiting, since sleeping that close to the target tends to overshoot it.
+pub struct Scheduler<C: Clock> {
+    clock: C,
+    start: Duration,
+    spin: Duration,
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn new(clock: C, spin: Duration) -> Self {
+        let start = clock.now();
+        Self { clock, start, spin }
+    }
+
+    /// Blocks until `target` after the start and returns how late it woke up.
+    pub fn wait_until(&self, target: Duration) -> Duration {
+        let remaining = target.saturating_sub(self.elapsed());
+        if remaining > self.spin {
+            self.clock.sleep(remaining - self.spin);
+        }
+
+        let mut elapsed = self.elapsed();
+        while elapsed < target {
+            spin_loop();
+            elapsed = self.elapsed();
+        }
+
+        elapsed - target
+    }
+
+    /// Like `wait_until`, but gives up and returns `None` as soon as a stop is requested on `stop`
+    /// (or its sender is dropped).
+    pub fn wait_until_or_stopped(&self, target: Duration, stop: &Receiver<()>) -> Option<Duration> {
+        loop {
+            if let Ok(()) | Err(TryRecvError::Disconnected) = stop.try_recv() {
+                return None;
+            }
+
+            let remaining = target.saturating_sub(self.elapsed());
+            if remaining <= self.spin {
+                return Some(self.wait_until(target));
+            }
+            self.clock.sleep((remaining - self.spin).min(STOP_POLL));
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_sub(self.start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crossbeam_channel::bounded;
+
+    use super::*;
+
+    /// Clock for measuring a `Scheduler` without waiting in real time.
+    ///
+    /// Every sleep overshoots by `overshoot`, like a real sleep does, and every reading advances the
+    /// clock by `tick`, so spin-waiting makes progress.
+    #[derive(Debug)]
+    struct FakeClock {
+        now: Cell<Duration>,
+        overshoot: Duration,
+        tick: Duration,
+    }
+
+    impl FakeClock {
+        fn new(overshoot: Duration, tick: Duration) -> Self {
+            Self { now: Cell::new(Duration::ZERO), overshoot, tick }
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            let now = self.now.get();
+            self.now.set(now + self.tick);
+            now
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration + self.overshoot);
+        }
+    }
+
+    /// Runs a scheduler over `targets` and returns the worst and the mean lateness.
+    fn measure_error<C: Clock>(scheduler: &Scheduler<C>, targets: &[Duration]) -> (Duration, Duration) {
+        let errors: Vec<Duration> = targets
+            .iter()
+            .map(|target| scheduler.wait_until(*target))
+            .collect();
+
+        let worst = errors.iter().max().copied().unwrap_or_default();
+        let mean = match errors.len() {
+            0 => Duration::ZERO,
+            n => errors.iter().sum::<Duration>() / (n as u32),
+        };
+
+        (worst, mean)
+    }
+
+    // A target every 10 ms
+    fn targets(count: u32) -> Vec<Duration> {
+        (1..=count).map(|index| Duration::from_millis(10) * index).collect()
+    }
+
+    #[test]
+    fn spinning_absorbs_the_sleep_overshoot() {
+        let clock = FakeClock::new(Duration::from_micros(200), Duration::from_micros(1));
+        let scheduler = Scheduler::new(clock, Duration::from_micros(300));
+
+        let (worst, mean) = measure_error(&scheduler, &targets(1_000));
+
+        assert!(worst <= Duration::from_micros(1), "worst lateness {:?}", worst);
+        assert!(mean <= Duration::from_micros(1), "mean lateness {:?}", mean);
+    }
+
+    #[test]
+    fn lateness_is_bounded_by_what_spinning_leaves_of_the_overshoot() {
+        let clock = FakeClock::new(Duration::from_micros(500), Duration::from_micros(1));
+        let scheduler = Scheduler::new(clock, Duration::from_micros(300));
+
+        let (worst, mean) = measure_error(&scheduler, &targets(1_000));
+
+        assert!(worst <= Duration::from_micros(201), "worst lateness {:?}", worst);
+        assert!(mean <= Duration::from_micros(201), "mean lateness {:?}", mean);
+    }
+
+    #[test]
+    fn lateness_does_not_grow_with_the_number_of_targets() {
+        let measure = |count| {
+            let clock = FakeClock::new(Duration::from_micros(500), Duration::from_micros(3));
+            measure_error(&Scheduler::new(clock, Duration::from_micros(300)), &targets(count))
+        };
+
+        let (few_worst, few_mean) = measure(10);
+        let (many_worst, many_mean) = measure(100_000);
+
+        assert_eq!(many_worst, few_worst);
+        assert!(many_mean <= few_mean + Duration::from_micros(3), "{:?} against {:?}", many_mean, few_mean);
+    }
+
+    #[test]
+    fn wait_gives_up_once_stopped() {
+        let clock = FakeClock::new(Duration::ZERO, Duration::from_micros(1));
+        let scheduler = Scheduler::new(clock, Duration::from_micros(300));
+        let (stop, receiver) = bounded(1);
+
+        assert!(scheduler.wait_until_or_stopped(Duration::from_millis(10), &receiver).is_some());
+        stop.send(()).unwrap();
+        assert_eq!(scheduler.wait_until_or_stopped(Duration::from_secs(60), &receiver), None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError };
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam_channel::Sender;
+use serde::Serialize;
+
+use crate::capture::CaptureBuffer;
+use crate::error::PianoError;
+use crate::journal;
+use crate::recording::Recording;
+
+pub type SessionId = u32;
+
+/// What a session thread hands back when it ends.
+pub type SessionOutcome = Result<Option<Recording>, PianoError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SessionKind {
+    Listen,
+    Record,
+    Play,
+    Punch,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    id: SessionId,
+    kind: SessionKind,
+    finished: bool,
+}
+
+struct Session {
+    kind: SessionKind,
+    handle: JoinHandle<SessionOutcome>,
+    stop_sender: Sender<()>,
+    journal: Option<PathBuf>,
+}
+
+/// Owns every running listen, record, play and punch-in session along with the recordings they
+/// produce.
+///
+/// Sessions are independent of each other, so listening while a recording plays back is fine.
+/// The maps only ever hold plain data, so a lock poisoned by a panicking command is still used.
+pub struct SessionManager {
+    next_id: Mutex<SessionId>,
+    sessions: Mutex<HashMap<SessionId, Session>>,
+    recordings: Mutex<HashMap<String, Recording>>,
+    /// Journals still backing recordings recovered after a crash, by recording name
+    recovered: Mutex<HashMap<String, PathBuf>>,
+    capture: Arc<Mutex<CaptureBuffer>>,
+}
+
+impl SessionManager {
+    pub fn new(capture_window: Duration) -> Self {
+        Self {
+            next_id: Mutex::new(0),
+            sessions: Mutex::new(HashMap::new()),
+            recordings: Mutex::new(HashMap::new()),
+            recovered: Mutex::new(HashMap::new()),
+            capture: Arc::new(Mutex::new(CaptureBuffer::new(capture_window))),
+        }
+    }
+
+    /// Registers a session thread, which must end once anything arrives on the receiving end of
+    /// `stop_sender`.
+    pub fn insert(
+        &self,
+        kind: SessionKind,
+        handle: JoinHandle<SessionOutcome>,
+        stop_sender: Sender<()>,
+        journal: Option<PathBuf>
+    ) -> SessionId {
+        let id = {
+            let mut next_id = lock(&self.next_id);
+            *next_id += 1;
+            *next_id
+        };
+        lock(&self.sessions).insert(id, Session { kind, handle, stop_sender, journal });
+
+        id
+    }
+
+    /// Stops a session, waits for its thread and returns what it recorded, if anything.
+    ///
+    /// The journal of a record session goes with it, so a take that is stopped rather than ended
+    /// doesn't come back as a recovered one.
+    pub fn stop(&self, id: SessionId) -> Result<Option<Recording>, PianoError> {
+        let session = lock(&self.sessions).remove(&id).ok_or(PianoError::SessionNotFound(id))?;
+
+        // The session may already have ended on its own, in which case nobody is receiving
+        let _ = session.stop_sender.send(());
+        let outcome = match session.handle.join() {
+            Ok(outcome) => outcome,
+            Err(_) => Err(PianoError::SessionPanicked),
+        };
+
+        let outcome = match (outcome, &session.journal) {
+            (Ok(recording), _) => Ok(recording),
+            // Whatever reached the journal is the take
+            (Err(e), Some(path)) => journal::read(path).map(Some).map_err(|_| e),
+            (Err(e), None) => Err(e),
+        };
+        if let Some(path) = &session.journal {
+            journal::discard(path);
+        }
+
+        outcome
+    }
+
+    /// Stops a record session and stores its take as `name`.
+    pub fn end_recording(&self, id: SessionId, name: String) -> Result<(), PianoError> {
+        match lock(&self.sessions).get(&id) {
+            Some(session) if session.kind == SessionKind::Record => {}
+            _ => {
+                return Err(PianoError::SessionNotFound(id));
+            }
+        }
+
+        if let Some(recording) = self.stop(id)? {
+            self.insert_recording(name, recording);
+        }
+
+        Ok(())
+    }
+
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = lock(&self.sessions)
+            .iter()
+            .map(|(id, session)| SessionInfo {
+                id: *id,
+                kind: session.kind,
+                finished: session.handle.is_finished(),
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.id);
+
+        sessions
+    }
+
+    pub fn is_running(&self, kind: SessionKind) -> bool {
+        lock(&self.sessions)
+            .values()
+            .any(|session| session.kind == kind && !session.handle.is_finished())
+    }
+
+    pub fn recording(&self, name: &str) -> Result<Recording, PianoError> {
+        lock(&self.recordings)
+            .get(name)
+            .cloned()
+            .ok_or_else(|| PianoError::RecordingNotFound(name.to_string()))
+    }
+
+    pub fn insert_recording(&self, name: String, recording: Recording) {
+        lock(&self.recordings).insert(name, recording);
+    }
+
+    /// Stores a take recovered from `journal`, which is kept until the take is saved or discarded.
+    pub fn insert_recovered(&self, name: String, recording: Recording, journal: PathBuf) {
+        lock(&self.recovered).insert(name.clone(), journal);
+        self.insert_recording(name, recording);
+    }
+
+    /// Names of the recovered takes that are neither saved nor discarded yet.
+    pub fn recovered(&self) -> Vec<String> {
+        let mut names: Vec<String> = lock(&self.recovered).keys().cloned().collect();
+        names.sort();
+
+        names
+    }
+
+    /// Keeps a recovered take as `new_name` and lets go of its journal.
+    pub fn save_recovered(&self, name: &str, new_name: String) -> Result<(), PianoError> {
+        let journal = lock(&self.recovered)
+            .remove(name)
+            .ok_or_else(|| PianoError::RecordingNotFound(name.to_string()))?;
+        let recording = lock(&self.recordings).remove(name);
+        if let Some(recording) = recording {
+            self.insert_recording(new_name, recording);
+        }
+        journal::discard(&journal);
+
+        Ok(())
+    }
+
+    /// Throws a recovered take away along with its journal.
+    pub fn discard_recovered(&self, name: &str) -> Result<(), PianoError> {
+        let journal = lock(&self.recovered)
+            .remove(name)
+            .ok_or_else(|| PianoError::RecordingNotFound(name.to_string()))?;
+        lock(&self.recordings).remove(name);
+        journal::discard(&journal);
+
+        Ok(())
+    }
+
+    pub fn capture(&self) -> Arc<Mutex<CaptureBuffer>> {
+        Arc::clone(&self.capture)
+    }
+
+    pub fn save_capture(&self, name: String) -> Result<(), PianoError> {
+        let recording = {
+            let capture = lock(&self.capture);
+            if capture.is_empty() {
+                return Err(PianoError::NothingCaptured);
+            }
+            capture.to_recording()
+        };
+        self.insert_recording(name, recording);
+
+        Ok(())
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,327 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::error::PianoError;
+
+/// Status byte of an MTC quarter frame.
+pub const QUARTER_FRAME: u8 = 0xf1;
+
+// Start of a full-frame message, a universal real-time SysEx to every device
+const FULL_FRAME: [u8; 5] = [0xf0, 0x7f, 0x7f, 0x01, 0x01];
+const SYSEX_END: u8 = 0xf7;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FrameRate {
+    Fps24,
+    Fps25,
+    /// 29.97 drop-frame, which skips frame numbers to keep up with the clock
+    Fps2997,
+    Fps30,
+}
+
+impl FrameRate {
+    /// Frames counted in a second of timecode, which is 30 at 29.97 as well.
+    pub fn nominal(self) -> u64 {
+        match self {
+            Self::Fps24 => 24,
+            Self::Fps25 => 25,
+            Self::Fps2997 | Self::Fps30 => 30,
+        }
+    }
+
+    /// Time from the start of the timeline to frame number `frame`.
+    pub fn frame_time(self, frame: u64) -> Duration {
+        self.quarter_frame_time(frame * 4)
+    }
+
+    // Frames per second as a fraction, so 29.97 doesn't drift
+    fn ratio(self) -> (u128, u128) {
+        match self {
+            Self::Fps24 => (24, 1),
+            Self::Fps25 => (25, 1),
+            Self::Fps2997 => (30000, 1001),
+            Self::Fps30 => (30, 1),
+        }
+    }
+
+    fn quarter_frame_time(self, quarter_frames: u64) -> Duration {
+        let (frames, seconds) = self.ratio();
+        Duration::from_nanos(((quarter_frames as u128) * NANOS_PER_SECOND * seconds / (frames * 4)) as u64)
+    }
+
+    // The two bits MTC sends the rate as
+    fn code(self) -> u8 {
+        match self {
+            Self::Fps24 => 0,
+            Self::Fps25 => 1,
+            Self::Fps2997 => 2,
+            Self::Fps30 => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code & 0x03 {
+            0 => Self::Fps24,
+            1 => Self::Fps25,
+            2 => Self::Fps2997,
+            _ => Self::Fps30,
+        }
+    }
+}
+
+/// A position on a video timeline, as hours, minutes, seconds and frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+}
+
+impl Timecode {
+    /// The timecode of frame number `frame` from the start of the timeline.
+    pub fn from_frame(frame: u64, rate: FrameRate) -> Self {
+        let mut frame = frame;
+        // Drop-frame skips the first two frame numbers of every minute but each tenth
+        if rate == FrameRate::Fps2997 {
+            let (tens, rest) = (frame / 17982, frame % 17982);
+            frame += 18 * tens + if rest > 1 { 2 * ((rest - 2) / 1798) } else { 0 };
+        }
+
+        let fps = rate.nominal();
+        Self {
+            hours: ((frame / (fps * 3600)) % 24) as u8,
+            minutes: ((frame / (fps * 60)) % 60) as u8,
+            seconds: ((frame / fps) % 60) as u8,
+            frames: (frame % fps) as u8,
+        }
+    }
+
+    /// Number of the frame from the start of the timeline.
+    pub fn to_frame(self, rate: FrameRate) -> u64 {
+        let minutes = (self.hours as u64) * 60 + (self.minutes as u64);
+        let frame = (minutes * 60 + (self.seconds as u64)) * rate.nominal() + (self.frames as u64);
+        match rate {
+            FrameRate::Fps2997 => frame.saturating_sub(2 * (minutes - minutes / 10)),
+            _ => frame,
+        }
+    }
+
+    pub fn validate(self, rate: FrameRate) -> Result<(), PianoError> {
+        if self.hours >= 24 || self.minutes >= 60 || self.seconds >= 60 {
+            return Err(PianoError::Timecode(format!("{} is out of range", self)));
+        }
+        if (self.frames as u64) >= rate.nominal() {
+            return Err(PianoError::Timecode(format!("{} has more frames than a second", self)));
+        }
+        let dropped = rate == FrameRate::Fps2997 && self.seconds == 0 && !self.minutes.is_multiple_of(10);
+        if dropped && self.frames < 2 {
+            return Err(PianoError::Timecode(format!("{} is skipped by drop-frame", self)));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Timecode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}:{:02}", self.hours, self.minutes, self.seconds, self.frames)
+    }
+}
+
+/// How playback lines up with an external timeline through MIDI Time Code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TimecodeSync {
+    /// Sends MTC along with the recording, which starts at `start` on the timeline
+    Generate {
+        rate: FrameRate,
+        start: Timecode,
+    },
+    /// Follows the MTC coming in on the input port `input`, the recording starting at `start` on
+    /// that timeline
+    Chase {
+        input: String,
+        rate: FrameRate,
+        start: Timecode,
+    },
+}
+
+/// The full-frame message that locates the receiver to `timecode`.
+pub fn full_frame(timecode: Timecode, rate: FrameRate) -> Vec<u8> {
+    let mut message = FULL_FRAME.to_vec();
+    message.extend([
+        (rate.code() << 5) | (timecode.hours & 0x1f),
+        timecode.minutes & 0x3f,
+        timecode.seconds & 0x3f,
+        timecode.frames & 0x1f,
+        SYSEX_END,
+    ]);
+    message
+}
+
+/// The eight quarter frames spelling out `timecode`, which take two frames to send.
+pub fn quarter_frames(timecode: Timecode, rate: FrameRate) -> [[u8; 2]; 8] {
+    let values = [
+        timecode.frames & 0x0f,
+        (timecode.frames >> 4) & 0x01,
+        timecode.seconds & 0x0f,
+        (timecode.seconds >> 4) & 0x03,
+        timecode.minutes & 0x0f,
+        (timecode.minutes >> 4) & 0x03,
+        timecode.hours & 0x0f,
+        (rate.code() << 1) | ((timecode.hours >> 4) & 0x01),
+    ];
+
+    let mut messages = [[QUARTER_FRAME, 0]; 8];
+    for (piece, value) in values.into_iter().enumerate() {
+        messages[piece][1] = ((piece as u8) << 4) | value;
+    }
+    messages
+}
+
+/// MTC for playing `length` of a recording that starts at `start` on the timeline: a full frame
+/// to locate first, then quarter frames all the way, each with the time into the recording it is
+/// due at.
+pub fn generate(start: Timecode, rate: FrameRate, length: Duration) -> Vec<(Duration, Vec<u8>)> {
+    let first = start.to_frame(rate);
+    let origin = rate.frame_time(first);
+    let mut messages = vec![(Duration::ZERO, full_frame(start, rate))];
+
+    let mut frame = first;
+    while rate.frame_time(frame) - origin <= length {
+        let timecode = Timecode::from_frame(frame, rate);
+        for (quarter, message) in quarter_frames(timecode, rate).into_iter().enumerate() {
+            let at = rate.quarter_frame_time(frame * 4 + (quarter as u64)) - origin;
+            messages.push((at, message.to_vec()));
+        }
+        frame += 2;
+    }
+
+    messages
+}
+
+/// Follows incoming MTC and works out where its timeline is.
+#[derive(Debug, Default)]
+pub struct TimecodeReader {
+    pieces: [u8; 8],
+    next_piece: u8,
+}
+
+impl TimecodeReader {
+    /// Takes in a message, returning the time on the timeline it marks if it completes a timecode.
+    /// Quarter frames complete one every two frames, a full frame right away.
+    pub fn read(&mut self, message: &[u8]) -> Option<Duration> {
+        match *message {
+            [QUARTER_FRAME, data] => {
+                let piece = (data >> 4) & 0x07;
+                // A piece out of order means a dropout or a jump, so the timecode starts over
+                if piece != self.next_piece && piece != 0 {
+                    self.next_piece = 0;
+                    return None;
+                }
+                self.pieces[piece as usize] = data & 0x0f;
+                self.next_piece = (piece + 1) % 8;
+                if piece != 7 {
+                    return None;
+                }
+
+                let pieces = self.pieces;
+                let rate = FrameRate::from_code(pieces[7] >> 1);
+                let timecode = Timecode {
+                    hours: pieces[6] | ((pieces[7] & 0x01) << 4),
+                    minutes: pieces[4] | (pieces[5] << 4),
+                    seconds: pieces[2] | (pieces[3] << 4),
+                    frames: pieces[0] | (pieces[1] << 4),
+                };
+                // The last piece comes seven quarter frames after the frame they spell out
+                Some(rate.quarter_frame_time(timecode.to_frame(rate) * 4 + 7))
+            }
+            [0xf0, 0x7f, _, 0x01, 0x01, hours, minutes, seconds, frames, SYSEX_END] => {
+                let rate = FrameRate::from_code(hours >> 5);
+                let timecode = Timecode { hours: hours & 0x1f, minutes, seconds, frames };
+                self.next_piece = 0;
+                Some(rate.frame_time(timecode.to_frame(rate)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timecode(hours: u8, minutes: u8, seconds: u8, frames: u8) -> Timecode {
+        Timecode { hours, minutes, seconds, frames }
+    }
+
+    #[test]
+    fn drop_frame_skips_the_first_two_frames_of_most_minutes() {
+        let rate = FrameRate::Fps2997;
+
+        assert_eq!(Timecode::from_frame(1_799, rate), timecode(0, 0, 59, 29));
+        assert_eq!(Timecode::from_frame(1_800, rate), timecode(0, 1, 0, 2));
+        assert_eq!(Timecode::from_frame(17_982, rate), timecode(0, 10, 0, 0));
+        assert_eq!(timecode(0, 10, 0, 0).to_frame(rate), 17_982);
+        assert!(timecode(0, 1, 0, 1).validate(rate).is_err());
+        assert!(timecode(0, 10, 0, 1).validate(rate).is_ok());
+    }
+
+    #[test]
+    fn drop_frame_round_trips_every_frame_of_a_day() {
+        let rate = FrameRate::Fps2997;
+        let frames_a_day = 24 * 6 * 17_982;
+
+        for frame in 0..frames_a_day {
+            let timecode = Timecode::from_frame(frame, rate);
+            assert_eq!(timecode.to_frame(rate), frame, "{}", timecode);
+            assert!(timecode.validate(rate).is_ok(), "{}", timecode);
+        }
+        assert_eq!(Timecode::from_frame(frames_a_day, rate), timecode(0, 0, 0, 0));
+    }
+
+    #[test]
+    fn whole_frame_rates_round_trip() {
+        for rate in [FrameRate::Fps24, FrameRate::Fps25, FrameRate::Fps30] {
+            let frames_a_day = 24 * 3600 * rate.nominal();
+            for frame in (0..frames_a_day).step_by(997) {
+                assert_eq!(Timecode::from_frame(frame, rate).to_frame(rate), frame);
+            }
+        }
+    }
+
+    #[test]
+    fn drop_frame_time_does_not_drift() {
+        assert_eq!(FrameRate::Fps2997.frame_time(30_000 * 3_600), Duration::from_secs(1_001 * 3_600));
+    }
+
+    #[test]
+    fn reads_back_what_it_sends() {
+        let rate = FrameRate::Fps25;
+        let start = timecode(1, 2, 3, 4);
+        let mut reader = TimecodeReader::default();
+
+        assert_eq!(reader.read(&full_frame(start, rate)), Some(rate.frame_time(start.to_frame(rate))));
+
+        let quarter_frames = quarter_frames(start, rate);
+        let read: Vec<Option<Duration>> = quarter_frames.iter().map(|message| reader.read(message)).collect();
+        let last = rate.quarter_frame_time(start.to_frame(rate) * 4 + 7);
+        assert_eq!(read, [vec![None; 7], vec![Some(last)]].concat());
+    }
+
+    #[test]
+    fn starts_over_after_a_piece_out_of_order() {
+        let rate = FrameRate::Fps30;
+        let pieces = quarter_frames(timecode(0, 0, 10, 0), rate);
+        let mut reader = TimecodeReader::default();
+
+        for piece in [0, 1, 2, 4, 5, 6, 7] {
+            assert_eq!(reader.read(&pieces[piece]), None);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex, PoisonError };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::piano_listen::StateCode;
+
+// Polyphonic key pressure, the only other channel message that names a note
+const KEY_PRESSURE: u8 = 0xa0;
+
+/// How far notes are moved, as an octave shift on top of a transpose in semitones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transpose {
+    pub semitones: i8,
+    pub octaves: i8,
+}
+
+impl Transpose {
+    /// The total shift in semitones.
+    pub fn shift(self) -> i32 {
+        (self.semitones as i32) + 12 * (self.octaves as i32)
+    }
+}
+
+/// The transpose applied to live input, shared between the commands changing it and the listeners
+/// reading it. A change takes effect at the next key press.
+#[derive(Debug, Clone, Default)]
+pub struct TransposeControl(Arc<Mutex<Transpose>>);
+
+impl TransposeControl {
+    pub fn get(&self) -> Transpose {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn set(&self, transpose: Transpose) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = transpose;
+    }
+}
+
+/// Moves the notes of one input's messages by a transpose that may change at any time.
+///
+/// Every press remembers the note it was moved to, so its release (and any key pressure) goes to
+/// the same note even if the transpose changed while the key was held. Presses moved off the end
+/// of the MIDI range are dropped along with their releases. `map` does the same for any other way
+/// of picking notes, like snapping them to a scale.
+#[derive(Debug, Default)]
+pub struct Transposer {
+    held: HashMap<(u8, u8), Option<u8>>,
+}
+
+impl Transposer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The message moved by `shift` semitones, `None` if it is dropped. Anything that isn't about
+    /// a note passes through unchanged.
+    pub fn apply(&mut self, message: &[u8], shift: i32) -> Option<Vec<u8>> {
+        self.map(message, |note| moved(note, shift))
+    }
+
+    /// Like `apply`, with the note of each press picked by `mapping` (`None` to drop it).
+    pub fn map<M>(&mut self, message: &[u8], mapping: M) -> Option<Vec<u8>> where M: Fn(u8) -> Option<u8> {
+        if message.len() < 3 || !(0x80..0xb0).contains(&message[0]) {
+            return Some(message.to_vec());
+        }
+        let status = message[0] & 0xf0;
+        let key = (message[0] & 0x0f, message[1]);
+
+        let note = if status == (StateCode::KeyPress as u8) && message[2] > 0 {
+            let note = mapping(message[1]);
+            self.held.insert(key, note);
+            note
+        } else if status == KEY_PRESSURE {
+            match self.held.get(&key) {
+                Some(note) => *note,
+                None => mapping(message[1]),
+            }
+        } else {
+            // A release, either a note-off or a note-on without velocity. A key pressed before the
+            // transposer saw it can only be guessed at, so it goes by the current mapping.
+            match self.held.remove(&key) {
+                Some(note) => note,
+                None => mapping(message[1]),
+            }
+        };
+
+        note.map(|note| {
+            let mut message = message.to_vec();
+            message[1] = note;
+            message
+        })
+    }
+}
+
+/// `note` moved by `shift` semitones, `None` if that leaves the MIDI range.
+pub fn moved(note: u8, shift: i32) -> Option<u8> {
+    u8::try_from((note as i32) + shift)
+        .ok()
+        .filter(|note| *note <= 0x7f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_goes_to_the_note_the_press_was_moved_to() {
+        let mut transposer = Transposer::new();
+
+        assert_eq!(transposer.apply(&[0x90, 60, 100], 2), Some(vec![0x90, 62, 100]));
+        assert_eq!(transposer.apply(&[0x91, 60, 100], 2), Some(vec![0x91, 62, 100]));
+        // The transpose changes while both keys are held
+        assert_eq!(transposer.apply(&[0xa0, 60, 30], 7), Some(vec![0xa0, 62, 30]));
+        assert_eq!(transposer.apply(&[0x80, 60, 0], 7), Some(vec![0x80, 62, 0]));
+        assert_eq!(transposer.apply(&[0x91, 60, 0], -12), Some(vec![0x91, 62, 0]));
+        // Released keys go by the transpose again
+        assert_eq!(transposer.apply(&[0x90, 60, 100], 7), Some(vec![0x90, 67, 100]));
+    }
+
+    #[test]
+    fn drops_presses_moved_out_of_range_along_with_their_release() {
+        let mut transposer = Transposer::new();
+
+        assert_eq!(transposer.apply(&[0x90, 120, 100], 12), None);
+        assert_eq!(transposer.apply(&[0x80, 120, 0], 0), None);
+        assert_eq!(transposer.apply(&[0x90, 5, 100], -6), None);
+        assert_eq!(transposer.apply(&[0x90, 5, 100], -5), Some(vec![0x90, 0, 100]));
+    }
+
+    #[test]
+    fn passes_anything_but_notes_through() {
+        let mut transposer = Transposer::new();
+
+        for message in [&[0xb0, 64, 127][..], &[0xe0, 0, 64], &[0xf8], &[0xc0, 5]] {
+            assert_eq!(transposer.apply(message, 3).as_deref(), Some(message));
+        }
+    }
+
+    #[test]
+    fn shift_adds_the_octaves() {
+        assert_eq!(Transpose { semitones: -3, octaves: 2 }.shift(), 21);
+        assert_eq!(moved(127, 1), None);
+        assert_eq!(moved(0, -1), None);
+    }
+}
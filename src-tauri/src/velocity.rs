@@ -0,0 +1,207 @@
+use serde::{ Deserialize, Serialize };
+
+use crate::error::PianoError;
+
+// Intensities the calibration maps the player's soft, medium and loud playing to
+const CALIBRATION_TARGETS: [f32; 3] = [0.25, 0.55, 0.85];
+
+/// How a key velocity turns into an intensity between 0 and 1.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum VelocityCurve {
+    #[default]
+    Linear,
+    /// For keyboards that need a hard touch, soft playing comes out louder
+    Soft,
+    /// For light keyboards, soft playing comes out quieter
+    Hard,
+    /// Smooth curve through `(velocity, intensity)` points, both between 0 and 1
+    Custom(Vec<(f32, f32)>),
+}
+
+impl VelocityCurve {
+    pub fn apply(&self, velocity: u8) -> f32 {
+        self.apply_normalized((velocity as f32) / 127.0)
+    }
+
+    /// Like `apply`, for a velocity already scaled to between 0 and 1.
+    pub fn apply_normalized(&self, velocity: f32) -> f32 {
+        let intensity = match self {
+            Self::Linear => velocity,
+            Self::Soft => velocity.powf(0.6),
+            Self::Hard => velocity.powf(1.7),
+            Self::Custom(points) => spline(points, velocity),
+        };
+
+        intensity.clamp(0.0, 1.0)
+    }
+
+    /// Fits a curve to the median velocities of the player's soft, medium and loud playing.
+    pub fn calibrated(soft: u8, medium: u8, loud: u8) -> Result<Self, PianoError> {
+        if !(soft < medium && medium < loud) {
+            return Err(PianoError::Calibration("soft, medium and loud must get louder".to_string()));
+        }
+
+        let mut points = vec![(0.0, 0.0)];
+        for (velocity, target) in [soft, medium, loud].into_iter().zip(CALIBRATION_TARGETS) {
+            points.push(((velocity as f32) / 127.0, target));
+        }
+        if loud < 127 {
+            points.push((1.0, 1.0));
+        }
+
+        Ok(Self::Custom(points))
+    }
+}
+
+/// The velocity most of `velocities` were played at, for one calibration level.
+pub fn median(velocities: &[u8]) -> Option<u8> {
+    let mut velocities = velocities.to_vec();
+    velocities.sort_unstable();
+    velocities.get(velocities.len() / 2).copied()
+}
+
+// Monotone cubic (Fritsch-Carlson) interpolation, so a rising set of points never gives a curve
+// that dips in between them
+fn spline(points: &[(f32, f32)], x: f32) -> f32 {
+    let mut points = points.to_vec();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    points.dedup_by(|a, b| a.0 == b.0);
+
+    match points.as_slice() {
+        [] => {
+            return x;
+        }
+        [(_, y)] => {
+            return *y;
+        }
+        _ => {}
+    }
+
+    let n = points.len();
+    if x <= points[0].0 {
+        return points[0].1;
+    }
+    if x >= points[n - 1].0 {
+        return points[n - 1].1;
+    }
+
+    let slopes: Vec<f32> = points
+        .windows(2)
+        .map(|pair| (pair[1].1 - pair[0].1) / (pair[1].0 - pair[0].0))
+        .collect();
+
+    let mut tangents = vec![0.0; n];
+    tangents[0] = slopes[0];
+    tangents[n - 1] = slopes[n - 2];
+    for i in 1..n - 1 {
+        tangents[i] = if slopes[i - 1] * slopes[i] <= 0.0 {
+            0.0
+        } else {
+            (slopes[i - 1] + slopes[i]) / 2.0
+        };
+    }
+    for i in 0..n - 1 {
+        if slopes[i] == 0.0 {
+            tangents[i] = 0.0;
+            tangents[i + 1] = 0.0;
+            continue;
+        }
+        let a = tangents[i] / slopes[i];
+        let b = tangents[i + 1] / slopes[i];
+        let length = (a * a + b * b).sqrt();
+        if length > 3.0 {
+            tangents[i] = (3.0 * a * slopes[i]) / length;
+            tangents[i + 1] = (3.0 * b * slopes[i]) / length;
+        }
+    }
+
+    let i = points.windows(2).position(|pair| x < pair[1].0).unwrap_or(n - 2);
+    let ((x0, y0), (x1, y1)) = (points[i], points[i + 1]);
+    let h = x1 - x0;
+    let t = (x - x0) / h;
+    let (t2, t3) = (t * t, t * t * t);
+
+    (2.0 * t3 - 3.0 * t2 + 1.0) * y0 +
+        (t3 - 2.0 * t2 + t) * h * tangents[i] +
+        (-2.0 * t3 + 3.0 * t2) * y1 +
+        (t3 - t2) * h * tangents[i + 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The curve at 1001 evenly spread velocities
+    fn sampled(points: &[(f32, f32)]) -> Vec<f32> {
+        (0..=1000).map(|i| spline(points, (i as f32) / 1000.0)).collect()
+    }
+
+    #[test]
+    fn spline_passes_through_its_points() {
+        let points = [(0.0, 0.0), (0.2, 0.25), (0.45, 0.55), (0.7, 0.85), (1.0, 1.0)];
+
+        for (x, y) in points {
+            assert!((spline(&points, x) - y).abs() < 1e-6, "{} at {}", spline(&points, x), x);
+        }
+    }
+
+    #[test]
+    fn spline_never_dips_between_rising_points() {
+        let rising: [&[(f32, f32)]; 4] = [
+            &[(0.0, 0.0), (0.2, 0.25), (0.45, 0.55), (0.7, 0.85), (1.0, 1.0)],
+            // A steep jump between flat stretches, which an ordinary cubic overshoots
+            &[(0.0, 0.0), (0.4, 0.05), (0.45, 0.9), (1.0, 1.0)],
+            &[(0.0, 0.0), (0.05, 0.6), (0.1, 0.62), (0.9, 0.65), (1.0, 1.0)],
+            // Unsorted, as a hand-edited profile may have them
+            &[(1.0, 1.0), (0.0, 0.0), (0.5, 0.2)],
+        ];
+
+        for points in rising {
+            let curve = sampled(points);
+            for pair in curve.windows(2) {
+                assert!(pair[1] >= pair[0] - 1e-6, "{:?} dips from {} to {}", points, pair[0], pair[1]);
+            }
+            assert!(curve.iter().all(|y| (-1e-6..=1.0 + 1e-6).contains(y)), "{:?}", points);
+        }
+    }
+
+    #[test]
+    fn spline_stays_flat_between_equal_points() {
+        let points = [(0.0, 0.0), (0.3, 0.5), (0.6, 0.5), (1.0, 1.0)];
+
+        for i in 30..=60 {
+            assert_eq!(spline(&points, (i as f32) / 100.0), 0.5);
+        }
+    }
+
+    #[test]
+    fn calibration_maps_the_levels_to_their_targets() {
+        let curve = VelocityCurve::calibrated(30, 70, 110).unwrap();
+
+        for (velocity, target) in [30, 70, 110].into_iter().zip(CALIBRATION_TARGETS) {
+            assert!((curve.apply(velocity) - target).abs() < 1e-6);
+        }
+        assert_eq!(curve.apply(0), 0.0);
+        assert_eq!(curve.apply(127), 1.0);
+        assert!(VelocityCurve::calibrated(70, 70, 110).is_err());
+        assert!(VelocityCurve::calibrated(110, 70, 30).is_err());
+    }
+
+    #[test]
+    fn presets_bend_the_same_way_everywhere() {
+        for velocity in 1..127 {
+            let linear = VelocityCurve::Linear.apply(velocity);
+            assert!(VelocityCurve::Soft.apply(velocity) > linear);
+            assert!(VelocityCurve::Hard.apply(velocity) < linear);
+        }
+        for curve in [VelocityCurve::Linear, VelocityCurve::Soft, VelocityCurve::Hard] {
+            assert_eq!((curve.apply(0), curve.apply(127)), (0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn median_picks_the_middle_velocity() {
+        assert_eq!(median(&[90, 20, 60, 61, 100]), Some(61));
+        assert_eq!(median(&[]), None);
+    }
+}
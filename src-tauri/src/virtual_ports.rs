@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError };
+
+use midir::{ MidiInputConnection, MidiOutputConnection };
+
+use crate::backend::{ InputCallback, InputConnection, OutputConnection };
+use crate::error::PianoError;
+
+/// Name other software sees the app by when it opens its own ports.
+pub const CLIENT_NAME: &str = "virtual-piano";
+
+/// Port the app creates for other software to play into, listened to like any other input.
+pub const VIRTUAL_INPUT: &str = "virtual-piano in";
+
+/// Port the app creates for other software to take its playback and routes from.
+pub const VIRTUAL_OUTPUT: &str = "virtual-piano out";
+
+/// Whether the app can create its own ports here, which midir can with ALSA (Linux) and CoreMIDI.
+pub const SUPPORTED: bool = cfg!(unix);
+
+/// Whether `name` is one of the app's own virtual ports as the port listings show them. Those
+/// aren't offered as devices, which would loop the app's messages back into it.
+pub fn is_own_port(name: &str) -> bool {
+    name == VIRTUAL_INPUT || name == VIRTUAL_OUTPUT || name.starts_with(&format!("{}:", CLIENT_NAME))
+}
+
+/// The app's own input and output, created once and shared by every connection to them, so other
+/// software sees the same two ports for as long as the app runs.
+pub struct VirtualPorts {
+    input: Result<VirtualInput, PianoError>,
+    output: Result<Arc<Mutex<MidiOutputConnection>>, PianoError>,
+}
+
+impl VirtualPorts {
+    /// Creates both ports. A port that can't be created fails every connection to it instead.
+    pub fn create() -> Self {
+        let listeners = Listeners::default();
+        let dispatch = listeners.clone();
+        let input = create_input(move |stamp, message: &[u8], _: &mut ()| dispatch.dispatch(stamp, message))
+            .map(|connection| VirtualInput { _connection: Mutex::new(connection), listeners });
+        let output = create_output().map(|connection| Arc::new(Mutex::new(connection)));
+
+        Self { input, output }
+    }
+
+    /// Starts passing whatever arrives on the virtual input to `callback`, along with every other
+    /// connection to it.
+    pub fn connect_input(&self, callback: InputCallback) -> Result<Box<dyn InputConnection>, PianoError> {
+        let input = self.input.as_ref().map_err(Clone::clone)?;
+        Ok(Box::new(input.listeners.register(callback)))
+    }
+
+    pub fn connect_output(&self) -> Result<Box<dyn OutputConnection>, PianoError> {
+        let output = self.output.as_ref().map_err(Clone::clone)?;
+        Ok(Box::new(SharedOutput(Arc::clone(output))))
+    }
+}
+
+struct VirtualInput {
+    // Keeps the port open, what arrives on it goes to the listeners
+    _connection: Mutex<MidiInputConnection<()>>,
+    listeners: Listeners,
+}
+
+#[derive(Default)]
+struct Callbacks {
+    next_id: u64,
+    callbacks: HashMap<u64, InputCallback>,
+}
+
+/// The callbacks of the open connections to the virtual input.
+#[derive(Clone, Default)]
+struct Listeners(Arc<Mutex<Callbacks>>);
+
+impl Listeners {
+    fn register(&self, callback: InputCallback) -> Listener {
+        let mut callbacks = lock(&self.0);
+        callbacks.next_id += 1;
+        let id = callbacks.next_id;
+        callbacks.callbacks.insert(id, callback);
+
+        Listener { id, listeners: self.clone() }
+    }
+
+    fn dispatch(&self, stamp: u64, message: &[u8]) {
+        for callback in lock(&self.0).callbacks.values_mut() {
+            callback(stamp, message);
+        }
+    }
+}
+
+// A connection to the virtual input, its callback is let go of once closed or dropped
+struct Listener {
+    id: u64,
+    listeners: Listeners,
+}
+
+impl InputConnection for Listener {
+    fn close(self: Box<Self>) {}
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        lock(&self.listeners.0).callbacks.remove(&self.id);
+    }
+}
+
+// A connection to the virtual output, closing it leaves the port open for the others
+struct SharedOutput(Arc<Mutex<MidiOutputConnection>>);
+
+impl OutputConnection for SharedOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), PianoError> {
+        Ok(lock(&self.0).send(message)?)
+    }
+
+    fn close(self: Box<Self>) {}
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(unix)]
+fn create_input<C>(callback: C) -> Result<MidiInputConnection<()>, PianoError>
+    where C: FnMut(u64, &[u8], &mut ()) + Send + 'static
+{
+    use midir::os::unix::VirtualInput;
+    use midir::{ Ignore, MidiInput };
+
+    let mut midi_in = MidiInput::new(CLIENT_NAME)?;
+    midi_in.ignore(Ignore::None);
+    Ok(midi_in.create_virtual(VIRTUAL_INPUT, callback, ())?)
+}
+
+#[cfg(not(unix))]
+fn create_input<C>(_: C) -> Result<MidiInputConnection<()>, PianoError>
+    where C: FnMut(u64, &[u8], &mut ()) + Send + 'static
+{
+    Err(unsupported())
+}
+
+#[cfg(unix)]
+fn create_output() -> Result<MidiOutputConnection, PianoError> {
+    use midir::os::unix::VirtualOutput;
+    use midir::MidiOutput;
+
+    let midi_out = MidiOutput::new(CLIENT_NAME)?;
+    Ok(midi_out.create_virtual(VIRTUAL_OUTPUT)?)
+}
+
+#[cfg(not(unix))]
+fn create_output() -> Result<MidiOutputConnection, PianoError> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> PianoError {
+    PianoError::Midi("virtual ports aren't supported on this platform".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(u64, Vec<u8>)>>>;
+
+    fn recorder(received: &Received) -> InputCallback {
+        let received = Arc::clone(received);
+        Box::new(move |stamp, message: &[u8]| received.lock().unwrap().push((stamp, message.to_vec())))
+    }
+
+    #[test]
+    fn every_open_connection_gets_what_arrives() {
+        let listeners = Listeners::default();
+        let (first, second) = (Received::default(), Received::default());
+
+        let first_connection: Box<dyn InputConnection> = Box::new(listeners.register(recorder(&first)));
+        let second_connection = listeners.register(recorder(&second));
+        listeners.dispatch(1, &[0x90, 60, 100]);
+        first_connection.close();
+        listeners.dispatch(2, &[0x80, 60, 0]);
+        drop(second_connection);
+        listeners.dispatch(3, &[0x90, 62, 100]);
+
+        assert_eq!(*first.lock().unwrap(), [(1, vec![0x90, 60, 100])]);
+        assert_eq!(*second.lock().unwrap(), [(1, vec![0x90, 60, 100]), (2, vec![0x80, 60, 0])]);
+        assert!(lock(&listeners.0).callbacks.is_empty());
+    }
+}
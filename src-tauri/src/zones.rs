@@ -0,0 +1,310 @@
+use serde::{ Deserialize, Serialize };
+
+use crate::backend::{ Backend, OutputConnection };
+use crate::error::PianoError;
+use crate::piano_listen::{ PianoKeyCode, StateCode };
+use crate::routing::is_channel_message;
+use crate::transpose::{ moved, Transpose, Transposer };
+use crate::velocity::VelocityCurve;
+
+// Status of a program change, without its channel
+const PROGRAM_CHANGE: u8 = 0xc0;
+
+/// A range of keys with its own sound. Zones side by side split the keyboard, overlapping zones
+/// layer their sounds on the shared keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Zone {
+    pub name: String,
+    /// Lowest key of the zone, sent as its MIDI note number
+    #[serde(with = "key_number")]
+    pub low: PianoKeyCode,
+    /// Highest key of the zone, included in it
+    #[serde(with = "key_number")]
+    pub high: PianoKeyCode,
+    /// Applied on top of the live transpose
+    #[serde(default)]
+    pub transpose: Transpose,
+    /// Shapes the velocity the zone plays its notes with
+    #[serde(default)]
+    pub velocity_curve: VelocityCurve,
+    /// Name of the output port the zone plays on, if any
+    #[serde(default)]
+    pub output: Option<String>,
+    /// Channel (0 to 15) to play on, the incoming channel if not set
+    #[serde(default)]
+    pub channel: Option<u8>,
+    /// Program selected on the zone's channel when the zone is connected, e.g. 32 for a GM
+    /// acoustic bass or 48 for GM strings. A zone playing on the incoming channel selects it on
+    /// each channel before the first message it plays there.
+    #[serde(default)]
+    pub preset: Option<u8>,
+}
+
+impl Zone {
+    /// Whether the key with MIDI note number `note` is in the zone.
+    pub fn contains(&self, note: u8) -> bool {
+        (self.low as u8) <= note && note <= (self.high as u8)
+    }
+
+    /// The message as the zone plays it, `None` if it isn't for this zone.
+    ///
+    /// Notes are picked by the key that was played, before any transpose, so a split stays where
+    /// it is on the keyboard. `mapping` is what the live input does to a note (transpose, scale
+    /// lock), the zone's own transpose goes on top. Every other channel message (pedals, pitch
+    /// bend, ...) reaches every zone.
+    pub fn apply(
+        &self,
+        transposer: &mut Transposer,
+        message: &[u8],
+        mapping: &dyn Fn(u8) -> Option<u8>
+    ) -> Option<Vec<u8>> {
+        if message.is_empty() || !is_channel_message(message) {
+            return None;
+        }
+        let is_note = message.len() >= 3 && message[0] < 0xb0;
+        if is_note && !self.contains(message[1]) {
+            return None;
+        }
+
+        let shift = self.transpose.shift();
+        let mut message = transposer.map(message, |note| {
+            mapping(note).and_then(|note| moved(note, shift))
+        })?;
+        let is_press = is_note && (message[0] & 0xf0) == (StateCode::KeyPress as u8) && message[2] > 0;
+        if is_press {
+            // Never down to 0, which would turn the press into a release
+            message[2] = ((self.velocity_curve.apply(message[2]) * 127.0).round() as u8).max(1);
+        }
+        if let Some(channel) = self.channel {
+            message[0] = (message[0] & 0xf0) | (channel & 0x0f);
+        }
+
+        Some(message)
+    }
+}
+
+struct ConnectedZone {
+    zone: Zone,
+    connection: Option<Box<dyn OutputConnection>>,
+    /// Channels the zone's preset has been selected on, one bit each
+    preset_channels: u16,
+}
+
+impl ConnectedZone {
+    /// Selects the zone's preset on `channel` unless it already is.
+    fn select_preset(&mut self, channel: u8) -> Result<(), PianoError> {
+        let channel = channel & 0x0f;
+        if let (Some(preset), Some(connection)) = (self.zone.preset, &mut self.connection) {
+            if self.preset_channels & (1 << channel) == 0 {
+                connection.send(&[PROGRAM_CHANGE | channel, preset & 0x7f])?;
+                self.preset_channels |= 1 << channel;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a message the zone has applied to its output, if it has one.
+    fn play(&mut self, message: &[u8]) -> Result<(), PianoError> {
+        self.select_preset(message[0])?;
+        match &mut self.connection {
+            Some(connection) => connection.send(message),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Plays incoming messages on the outputs of the zones they fall in.
+///
+/// Zones keep track of held notes per input, so every input passes its own transposers (one per
+/// zone, from `transposers`) along with its messages.
+pub struct ZoneRouter {
+    zones: Vec<ConnectedZone>,
+}
+
+impl ZoneRouter {
+    /// Opens the output port of every zone that has one and selects its preset.
+    pub fn connect(backend: &Backend, zones: Vec<Zone>) -> Result<Self, PianoError> {
+        let mut connected = Vec::new();
+        for zone in zones {
+            if (zone.low as u8) > (zone.high as u8) {
+                return Err(PianoError::InvalidZone(format!("'{}' ends below where it starts", zone.name)));
+            }
+
+            let connection = match &zone.output {
+                Some(output) => Some(backend.connect_output(output)?),
+                None => None,
+            };
+            let mut zone = ConnectedZone { zone, connection, preset_channels: 0 };
+            // Without a channel of its own the zone waits for the incoming one
+            if let Some(channel) = zone.zone.channel {
+                zone.select_preset(channel)?;
+            }
+            connected.push(zone);
+        }
+
+        Ok(Self { zones: connected })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    pub fn transposers(&self) -> Vec<Transposer> {
+        self.zones
+            .iter()
+            .map(|_| Transposer::new())
+            .collect()
+    }
+
+    pub fn send(
+        &mut self,
+        transposers: &mut [Transposer],
+        message: &[u8],
+        mapping: &dyn Fn(u8) -> Option<u8>
+    ) {
+        for (zone, transposer) in self.zones.iter_mut().zip(transposers) {
+            if let Some(message) = zone.zone.apply(transposer, message, mapping) {
+                // A failing output mustn't hold up the input or the other zones
+                if let Err(e) = zone.play(&message) {
+                    println!("Failed to play zone '{}': {}", zone.zone.name, e);
+                }
+            }
+        }
+    }
+
+    pub fn close(self) {
+        for connection in self.zones.into_iter().filter_map(|zone| zone.connection) {
+            connection.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend::MockBackend;
+
+    const BASS: &str = "Bass";
+    const STRINGS: &str = "Strings";
+
+    fn zone(name: &str, low: PianoKeyCode, high: PianoKeyCode) -> Zone {
+        Zone {
+            name: name.to_string(),
+            low,
+            high,
+            transpose: Transpose::default(),
+            velocity_curve: VelocityCurve::default(),
+            output: Some(name.to_string()),
+            channel: None,
+            preset: None,
+        }
+    }
+
+    fn as_played(note: u8) -> Option<u8> {
+        Some(note)
+    }
+
+    fn play(router: &mut ZoneRouter, messages: &[&[u8]]) {
+        let mut transposers = router.transposers();
+        for message in messages {
+            router.send(&mut transposers, message, &as_played);
+        }
+    }
+
+    #[test]
+    fn split_point_belongs_to_the_zone_it_ends() {
+        let mock = MockBackend::new(&[], &[BASS, STRINGS]);
+        let zones = vec![
+            zone(BASS, PianoKeyCode::Eb0, PianoKeyCode::B4),
+            zone(STRINGS, PianoKeyCode::C4, PianoKeyCode::C8),
+        ];
+        let mut router = ZoneRouter::connect(&Backend::new(mock.clone()), zones).unwrap();
+
+        play(&mut router, &[&[0x90, 59, 100], &[0x90, 60, 100], &[0x90, 108, 100]]);
+
+        assert_eq!(mock.sent(BASS), vec![vec![0x90, 59, 100]]);
+        assert_eq!(mock.sent(STRINGS), vec![vec![0x90, 60, 100], vec![0x90, 108, 100]]);
+    }
+
+    #[test]
+    fn layered_zones_both_play_shared_keys() {
+        let mock = MockBackend::new(&[], &[BASS, STRINGS]);
+        let zones = vec![
+            zone(BASS, PianoKeyCode::Eb0, PianoKeyCode::C4),
+            zone(STRINGS, PianoKeyCode::C4, PianoKeyCode::C8),
+        ];
+        let mut router = ZoneRouter::connect(&Backend::new(mock.clone()), zones).unwrap();
+
+        play(&mut router, &[&[0x90, 60, 100], &[0xb0, 64, 127]]);
+
+        assert_eq!(mock.sent(BASS), vec![vec![0x90, 60, 100], vec![0xb0, 64, 127]]);
+        assert_eq!(mock.sent(STRINGS), vec![vec![0x90, 60, 100], vec![0xb0, 64, 127]]);
+    }
+
+    #[test]
+    fn zones_apply_their_own_transpose_and_curve() {
+        let mock = MockBackend::new(&[], &[BASS, STRINGS]);
+        let bass = Zone {
+            transpose: Transpose { semitones: 0, octaves: -1 },
+            velocity_curve: VelocityCurve::Custom(vec![(0.0, 0.0), (1.0, 0.5)]),
+            ..zone(BASS, PianoKeyCode::Eb0, PianoKeyCode::C8)
+        };
+        let strings = Zone {
+            transpose: Transpose { semitones: 7, octaves: 0 },
+            ..zone(STRINGS, PianoKeyCode::Eb0, PianoKeyCode::C8)
+        };
+        let mut router = ZoneRouter::connect(&Backend::new(mock.clone()), vec![bass, strings]).unwrap();
+
+        play(&mut router, &[&[0x90, 60, 127], &[0x80, 60, 0]]);
+
+        assert_eq!(mock.sent(BASS), vec![vec![0x90, 48, 64], vec![0x80, 48, 0]]);
+        assert_eq!(mock.sent(STRINGS), vec![vec![0x90, 67, 127], vec![0x80, 67, 0]]);
+    }
+
+    #[test]
+    fn presets_follow_the_incoming_channel_without_a_zone_channel() {
+        let mock = MockBackend::new(&[], &[BASS, STRINGS]);
+        let bass = Zone { preset: Some(32), ..zone(BASS, PianoKeyCode::Eb0, PianoKeyCode::C8) };
+        let strings = Zone {
+            preset: Some(48),
+            channel: Some(2),
+            ..zone(STRINGS, PianoKeyCode::Eb0, PianoKeyCode::C8)
+        };
+        let mut router = ZoneRouter::connect(&Backend::new(mock.clone()), vec![bass, strings]).unwrap();
+        assert!(mock.sent(BASS).is_empty());
+        assert_eq!(mock.sent(STRINGS), vec![vec![0xc2, 48]]);
+
+        play(&mut router, &[&[0x93, 60, 100], &[0x83, 60, 0], &[0x95, 60, 100]]);
+
+        assert_eq!(mock.sent(BASS), vec![
+            vec![0xc3, 32],
+            vec![0x93, 60, 100],
+            vec![0x83, 60, 0],
+            vec![0xc5, 32],
+            vec![0x95, 60, 100],
+        ]);
+        assert_eq!(mock.sent(STRINGS), vec![
+            vec![0xc2, 48],
+            vec![0x92, 60, 100],
+            vec![0x82, 60, 0],
+            vec![0x92, 60, 100],
+        ]);
+    }
+}
+
+// Keys as their MIDI note number, which is easier on the frontend than the variant names
+mod key_number {
+    use serde::de::Error;
+    use serde::{ Deserialize, Deserializer, Serializer };
+
+    use crate::piano_listen::PianoKeyCode;
+
+    pub fn serialize<S: Serializer>(key: &PianoKeyCode, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*key as u8)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PianoKeyCode, D::Error> {
+        let note = u8::deserialize(deserializer)?;
+        PianoKeyCode::checked(note).ok_or_else(|| D::Error::custom(format!("no piano key {}", note)))
+    }
+}
@@ -1,53 +1,244 @@
 import { useEffect, useState } from "react";
 import "./App.css";
-import { PianoView } from "./PianoView";
+import { PianoView, Zone, ZoneStrip } from "./PianoView";
 import { Event, emit, listen } from "@tauri-apps/api/event";
 import { invoke } from "@tauri-apps/api/core";
 
+type SessionInfo = { id: number; kind: "Listen" | "Record" | "Play" | "Punch"; finished: boolean };
+type PianoError = { kind: string; message: string };
+type Transpose = { semitones: number; octaves: number };
+type ScaleLock = { root: number; scale: string; mode: "Off" | "Snap" | "Mute" | "Highlight" };
+type ArpPattern = "Up" | "Down" | "UpDown" | "Random" | "AsPlayed";
+type ClockOutput = { output: string; bpm: number };
+// The app's own ports, which other software can play into and listen to
+const VIRTUAL_INPUT = "virtual-piano in";
+const VIRTUAL_OUTPUT = "virtual-piano out";
+
+type FrameRate = "Fps24" | "Fps25" | "Fps2997" | "Fps30";
+type TransportEvent = "Pulse" | "Start" | "Continue" | "Stop" | { Seek: number } | { Beat: number };
+
 function App() {
-  const [isRecording, setIsRecording] = useState(false);
-  const [isListening, setIsListening] = useState(false);
+  const [recordSession, setRecordSession] = useState<number | null>(null);
+  const [listenSession, setListenSession] = useState<number | null>(null);
+  const [error, setError] = useState<PianoError | null>(null);
+  const [disconnected, setDisconnected] = useState<string[]>([]);
+  const [calibrationPrompt, setCalibrationPrompt] = useState<string | null>(null);
+  const [transpose, setTranspose] = useState<Transpose>({ semitones: 0, octaves: 0 });
+  const [zones, setZones] = useState<Zone[]>([]);
+  const [arpPattern, setArpPattern] = useState<ArpPattern | null>(null);
+  const [chordMemory, setChordMemory] = useState(false);
+  const [scaleLock, setScaleLock] = useState<ScaleLock>({ root: 0, scale: "Major", mode: "Off" });
+  const [arpFollowsClock, setArpFollowsClock] = useState(false);
+  const [clockOutput, setClockOutput] = useState<ClockOutput | null>(null);
+  const [beat, setBeat] = useState<number | null>(null);
+  const [mtcRate, setMtcRate] = useState<FrameRate | null>(null);
+  const [virtualPorts, setVirtualPorts] = useState(false);
+  const [recovered, setRecovered] = useState<string[]>([]);
+  const isRecording = recordSession !== null;
+  const isListening = listenSession !== null;
+  // Anything not set falls back to the defaults: one octave of sixteenths at 120 bpm
+  const arpeggiator =
+    arpPattern === null ? null : { pattern: arpPattern, ...(arpFollowsClock && { sync: "MidiClock" }) };
 
   useEffect(() => {
-    async function updateIsListening() {
-      setIsListening(await invoke<boolean>("is_listening"));
+    async function restoreSessions() {
+      const sessions = await invoke<SessionInfo[]>("list_sessions");
+      const running = (kind: SessionInfo["kind"]) =>
+        sessions.find((session) => session.kind === kind && !session.finished)?.id ?? null;
+
+      setListenSession(running("Listen"));
+      setRecordSession(running("Record"));
     }
-    updateIsListening();
+    restoreSessions();
+    invoke<Transpose>("get_transpose").then(setTranspose);
+    invoke<ScaleLock>("get_scale_lock").then(setScaleLock);
+    invoke<string[]>("list_recovered_recordings").then(setRecovered);
 
-    listen<{ event_type: string; intensity: number; key_string: string; key_id: number }>(
+    listen<{
+      event_type: string;
+      source: string;
+      intensity: number;
+      velocity: number;
+      high_resolution_velocity: number | null;
+      out_of_scale: boolean;
+      transport: TransportEvent | null;
+      pressed: boolean;
+      key_string: string;
+      key_id: number;
+    }>(
       "pianoevent",
       (ev) => {
+        const transport = ev.payload.transport;
+        if (transport === "Stop") {
+          setBeat(null);
+        } else if (typeof transport === "object" && transport !== null && "Beat" in transport) {
+          setBeat(transport.Beat);
+        }
         const event = new CustomEvent("pianoevent", { detail: ev.payload });
         document.dispatchEvent(event);
       }
     );
+
+    listen<PianoError>("pianoerror", (ev) => setError(ev.payload));
+
+    // A running session reconnects on its own once the port is back
+    listen<string>("devicedisconnected", (ev) =>
+      setDisconnected((ports) => [...ports.filter((port) => port !== ev.payload), ev.payload])
+    );
+    listen<string>("deviceconnected", (ev) =>
+      setDisconnected((ports) => ports.filter((port) => port !== ev.payload))
+    );
   }, []);
 
+  // Commands reject with the same `{ kind, message }` shape as the `pianoerror` event
+  async function reportErrors(command: () => Promise<void>) {
+    try {
+      await command();
+    } catch (e) {
+      setError(e as PianoError);
+    }
+  }
+
+  // Every input along with the virtual one, or just the default input
+  async function inputPorts() {
+    const ports = await invoke<string[]>("list_input_ports");
+    return virtualPorts && ports.includes(VIRTUAL_INPUT) ? ports : null;
+  }
+
   async function startPianoListen() {
-    setIsListening((prev) => {
-      if (prev) {
-        invoke<boolean>("kill_piano_listener");
+    reportErrors(async () => {
+      if (listenSession !== null) {
+        setListenSession(null);
+        await invoke("stop_session", { id: listenSession });
       } else {
-        invoke<boolean>("spawn_piano_listener");
+        const ports = await inputPorts();
+        setListenSession(
+          await invoke<number>("spawn_piano_listener", { setup: { ports, zones, arpeggiator, clockOutput } })
+        );
       }
-
-      return !prev;
     });
   }
 
   async function startPianoRecorder() {
-    setIsRecording((prev) => {
-      if (prev) {
-        invoke<boolean>("end_piano_recording", {name: "First recording"});
+    reportErrors(async () => {
+      if (recordSession !== null) {
+        setRecordSession(null);
+        await invoke("end_piano_recording", { id: recordSession, name: "First recording" });
+        // Starting the recording at 00:00:00:00, for video lined up the same way
+        const timecode = mtcRate === null ? null : { Generate: { rate: mtcRate, start: {} } };
+        const output = virtualPorts ? VIRTUAL_OUTPUT : null;
+        await invoke<number>("play_recording", { name: "First recording", timecode, output });
+      } else {
+        const ports = await inputPorts();
+        setRecordSession(
+          await invoke<number>("spawn_piano_recorder", { setup: { ports, zones, arpeggiator, clockOutput } })
+        );
+      }
+    });
+  }
 
-        setTimeout(() => {
-          invoke<boolean>("play_recording")
-        }, 500);
+  // A take recovered after a crash keeps its journal until it is kept under its name or discarded
+  async function resolveRecovered(name: string, keep: boolean) {
+    reportErrors(async () => {
+      if (keep) {
+        await invoke("save_recovered_recording", { name, newName: name });
       } else {
-        invoke<boolean>("spawn_piano_recorder");
+        await invoke("discard_recovered_recording", { name });
+      }
+      setRecovered((names) => names.filter((recoveredName) => recoveredName !== name));
+    });
+  }
+
+  async function saveCapturedRecording() {
+    reportErrors(async () => {
+      await invoke("save_captured_recording", { name: `Captured ${new Date().toLocaleTimeString()}` });
+    });
+  }
+
+  // Has the player play a few keys softly, medium and loud, then fits a velocity curve to it
+  async function calibrateVelocity() {
+    reportErrors(async () => {
+      const [port] = await invoke<string[]>("list_input_ports");
+      if (port === undefined) {
+        throw { kind: "NoInputPort", message: "no input port found" };
+      }
+
+      try {
+        const prompts = { soft: "softly", medium: "at medium strength", loud: "loudly" };
+        const levels: Record<string, number> = {};
+        for (const [level, how] of Object.entries(prompts)) {
+          setCalibrationPrompt(`Play a few keys ${how}`);
+          levels[level] = await invoke<number>("calibrate_velocity", { port });
+        }
+        await invoke("save_velocity_calibration", { port, ...levels });
+      } finally {
+        setCalibrationPrompt(null);
+      }
+    });
+  }
+
+  // Takes effect on the next key press, held keys are released where they were pressed
+  async function shiftTranspose(semitones: number, octaves: number) {
+    reportErrors(async () => {
+      setTranspose(
+        await invoke<Transpose>("set_transpose", {
+          semitones: transpose.semitones + semitones,
+          octaves: transpose.octaves + octaves,
+        })
+      );
+    });
+  }
+
+  // Bass on the left and strings from middle C up, on the first output port
+  async function toggleSplit() {
+    reportErrors(async () => {
+      if (zones.length > 0) {
+        setZones([]);
+        return;
+      }
+
+      const [output] = await invoke<string[]>("list_output_ports");
+      setZones([
+        { name: "Bass", low: 21, high: 59, output: output ?? null, channel: 0, preset: 32 },
+        { name: "Strings", low: 60, high: 108, output: output ?? null, channel: 1, preset: 48 },
+      ]);
+    });
+  }
+
+  // Clock master on the first output port, for drum machines and sequencers to follow
+  async function toggleClockOutput() {
+    reportErrors(async () => {
+      if (clockOutput !== null) {
+        setClockOutput(null);
+        return;
+      }
+
+      const [output] = await invoke<string[]>("list_output_ports");
+      if (output !== undefined) {
+        setClockOutput({ output, bpm: 120 });
       }
+    });
+  }
+
+  // Every key plays the chord held while capturing, starting on that key
+  async function captureChord() {
+    reportErrors(async () => {
+      await invoke("capture_chord", { name: "Chord" });
+    });
+  }
 
-      return !prev;
+  async function toggleChordMemory() {
+    reportErrors(async () => {
+      await invoke("set_chord_memory", { enabled: !chordMemory });
+      setChordMemory(!chordMemory);
+    });
+  }
+
+  async function changeScaleLock(change: Partial<ScaleLock>) {
+    reportErrors(async () => {
+      const changed = { ...scaleLock, ...change };
+      await invoke("set_scale_lock", { scaleLock: changed });
+      setScaleLock(changed);
     });
   }
 
@@ -73,8 +264,196 @@ function App() {
             className={isRecording ? startColor : stopColor}
           ></button>
         </div>
+        <div>
+          <p>Save what I just played:</p>
+          <button
+            disabled={!isListening}
+            onMouseDown={saveCapturedRecording}
+            className={startColor}
+          ></button>
+        </div>
+        <div>
+          <p>Calibrate velocity:</p>
+          <button
+            disabled={calibrationPrompt !== null}
+            onMouseDown={calibrateVelocity}
+            className={startColor}
+          ></button>
+        </div>
+        <div>
+          <p>Split:</p>
+          <button
+            disabled={isListening || isRecording}
+            onMouseDown={toggleSplit}
+            className={zones.length > 0 ? startColor : stopColor}
+          ></button>
+        </div>
+        <div>
+          <p>Arpeggiator:</p>
+          <select
+            disabled={isListening || isRecording}
+            value={arpPattern ?? "Off"}
+            onChange={(ev) =>
+              setArpPattern(ev.target.value === "Off" ? null : (ev.target.value as ArpPattern))
+            }
+            className="h-8"
+          >
+            <option value="Off">Off</option>
+            <option value="Up">Up</option>
+            <option value="Down">Down</option>
+            <option value="UpDown">Up-down</option>
+            <option value="Random">Random</option>
+            <option value="AsPlayed">As played</option>
+          </select>
+        </div>
+        <div>
+          <p>Arpeggiator follows MIDI clock / send clock:</p>
+          <div className="flex gap-x-1">
+            <button
+              disabled={isListening || isRecording}
+              onMouseDown={() => setArpFollowsClock(!arpFollowsClock)}
+              className={arpFollowsClock ? startColor : stopColor}
+            ></button>
+            <button
+              disabled={isListening || isRecording}
+              onMouseDown={toggleClockOutput}
+              className={clockOutput !== null ? startColor : stopColor}
+            ></button>
+          </div>
+        </div>
+        <div>
+          <p>Virtual ports:</p>
+          <button
+            disabled={isListening || isRecording}
+            onMouseDown={() => setVirtualPorts(!virtualPorts)}
+            className={virtualPorts ? startColor : stopColor}
+          ></button>
+        </div>
+        <div>
+          <p>Send MTC on playback:</p>
+          <select
+            value={mtcRate ?? "Off"}
+            onChange={(ev) => setMtcRate(ev.target.value === "Off" ? null : (ev.target.value as FrameRate))}
+            className="h-8"
+          >
+            <option value="Off">Off</option>
+            <option value="Fps24">24 fps</option>
+            <option value="Fps25">25 fps</option>
+            <option value="Fps2997">29.97 fps drop-frame</option>
+            <option value="Fps30">30 fps</option>
+          </select>
+        </div>
+        <div>
+          <p>Beat:</p>
+          <p>{beat === null ? "-" : `${Math.floor(beat / 4) + 1}.${(beat % 4) + 1}`}</p>
+        </div>
+        <div>
+          <p>Capture chord / chord memory:</p>
+          <div className="flex gap-x-1">
+            <button
+              disabled={!isListening && !isRecording}
+              onMouseDown={captureChord}
+              className={startColor}
+            ></button>
+            <button
+              onMouseDown={toggleChordMemory}
+              className={chordMemory ? startColor : stopColor}
+            ></button>
+          </div>
+        </div>
+        <div>
+          <p>Scale lock:</p>
+          <div className="flex gap-x-1">
+            <select
+              value={scaleLock.root}
+              onChange={(ev) => changeScaleLock({ root: Number(ev.target.value) })}
+              className="h-8"
+            >
+              {["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"].map((name, root) => (
+                <option key={root} value={root}>
+                  {name}
+                </option>
+              ))}
+            </select>
+            <select
+              value={scaleLock.scale}
+              onChange={(ev) => changeScaleLock({ scale: ev.target.value })}
+              className="h-8"
+            >
+              {[
+                "Major",
+                "NaturalMinor",
+                "HarmonicMinor",
+                "MelodicMinor",
+                "Dorian",
+                "Mixolydian",
+                "MajorPentatonic",
+                "MinorPentatonic",
+                "Blues",
+              ].map((scale) => (
+                <option key={scale} value={scale}>
+                  {scale}
+                </option>
+              ))}
+            </select>
+            <select
+              value={scaleLock.mode}
+              onChange={(ev) => changeScaleLock({ mode: ev.target.value as ScaleLock["mode"] })}
+              className="h-8"
+            >
+              <option value="Off">Off</option>
+              <option value="Snap">Snap</option>
+              <option value="Mute">Mute</option>
+              <option value="Highlight">Highlight</option>
+            </select>
+          </div>
+        </div>
+        <div>
+          <p>
+            Transpose: {transpose.semitones}, octave: {transpose.octaves}
+          </p>
+          <div className="flex gap-x-1">
+            <button onMouseDown={() => shiftTranspose(0, -1)} className="h-8 px-2 bg-gray-300">
+              Oct -
+            </button>
+            <button onMouseDown={() => shiftTranspose(-1, 0)} className="h-8 px-2 bg-gray-300">
+              -
+            </button>
+            <button onMouseDown={() => shiftTranspose(1, 0)} className="h-8 px-2 bg-gray-300">
+              +
+            </button>
+            <button onMouseDown={() => shiftTranspose(0, 1)} className="h-8 px-2 bg-gray-300">
+              Oct +
+            </button>
+          </div>
+        </div>
+      </div>
+      {calibrationPrompt && <p>{calibrationPrompt}</p>}
+      {disconnected.length > 0 && (isListening || isRecording) && (
+        <p className="text-orange-600">
+          Waiting for {disconnected.join(", ")} to be reconnected
+        </p>
+      )}
+      {recovered.map((name) => (
+        <div key={name} className="flex gap-x-1">
+          <p>{name}</p>
+          <button onMouseDown={() => resolveRecovered(name, true)} className="h-8 px-2 bg-gray-300">
+            Keep
+          </button>
+          <button onMouseDown={() => resolveRecovered(name, false)} className="h-8 px-2 bg-gray-300">
+            Discard
+          </button>
+        </div>
+      ))}
+      {error && (
+        <p className="text-red-600 cursor-pointer" onMouseDown={() => setError(null)}>
+          {error.message}
+        </p>
+      )}
+      <div className="mt-auto">
+        <ZoneStrip zones={zones} />
       </div>
-      <div className="bg-red-500 w-screen mt-auto">
+      <div className="bg-red-500 w-screen">
         <PianoView />
       </div>
     </div>
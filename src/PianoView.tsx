@@ -2,6 +2,18 @@ import { Fragment, useEffect, useRef, useState } from "react";
 
 let next_key = 21;
 const activeColor = "red";
+// Keys played outside the scale while the scale lock highlights them
+const outOfScaleColor = "orange";
+const zoneColors = ["bg-blue-400", "bg-amber-400", "bg-emerald-400", "bg-fuchsia-400"];
+
+export type Zone = {
+  name: string;
+  low: number;
+  high: number;
+  output: string | null;
+  channel: number | null;
+  preset: number | null;
+};
 
 function getNextKey(): number {
   let key = next_key;
@@ -78,6 +90,7 @@ let increment = window.innerHeight / 700;
 
 function WhiteKey() {
   const [isActive, setIsActive] = useState(false);
+  const [isOutOfScale, setIsOutOfScale] = useState(false);
   const isActiveRef = useRef(false);
   const keyRef = useRef<HTMLDivElement>(null);
 
@@ -140,11 +153,13 @@ function WhiteKey() {
         intensity: number;
         key_string: string;
         key_id: number;
+        out_of_scale: boolean;
       }>;
 
       if (event.detail.key_id === keyId) {
         if (event.detail.event_type === "KeyPress") {
           setIsActive(true);
+          setIsOutOfScale(event.detail.out_of_scale);
           isActiveRef.current = true;
         } else if (event.detail.event_type === "KeyRelease") {
           setIsActive(false);
@@ -158,7 +173,7 @@ function WhiteKey() {
     <div
       className="relative ring-black ring-1 h-full w-[calc(69svw/52)] z-10"
       style={{
-        backgroundColor: isActive ? activeColor : "white",
+        backgroundColor: isActive ? (isOutOfScale ? outOfScaleColor : activeColor) : "white",
       }}
       ref={keyRef}
     ></div>
@@ -167,6 +182,7 @@ function WhiteKey() {
 
 function BlackKey({ multi }: { multi: number }) {
   const [isActive, setIsActive] = useState(false);
+  const [isOutOfScale, setIsOutOfScale] = useState(false);
   const prevRef = useRef<HTMLDivElement>(null);
   const nextRef = useRef<HTMLDivElement>(null);
   const isActiveRef = useRef(false);
@@ -233,11 +249,13 @@ function BlackKey({ multi }: { multi: number }) {
         intensity: number;
         key_string: string;
         key_id: number;
+        out_of_scale: boolean;
       }>;
 
       if (event.detail.key_id === keyId) {
         if (event.detail.event_type === "KeyPress") {
           setIsActive(true);
+          setIsOutOfScale(event.detail.out_of_scale);
           isActiveRef.current = true;
         } else if (event.detail.event_type === "KeyRelease") {
           setIsActive(false);
@@ -246,7 +264,7 @@ function BlackKey({ multi }: { multi: number }) {
       } else if (event.detail.key_id === keyId - 1) {
         if (event.detail.event_type === "KeyPress") {
           let elem = prevRef.current as HTMLElement;
-          elem.style.backgroundColor = activeColor;
+          elem.style.backgroundColor = event.detail.out_of_scale ? outOfScaleColor : activeColor;
         } else if (event.detail.event_type === "KeyRelease") {
           let elem = prevRef.current as HTMLElement;
           elem.style.backgroundColor = "white";
@@ -254,7 +272,7 @@ function BlackKey({ multi }: { multi: number }) {
       } else if (event.detail.key_id === keyId + 1) {
         if (event.detail.event_type === "KeyPress") {
           let elem = nextRef.current as HTMLElement;
-          elem.style.backgroundColor = activeColor;
+          elem.style.backgroundColor = event.detail.out_of_scale ? outOfScaleColor : activeColor;
         } else if (event.detail.event_type === "KeyRelease") {
           let elem = nextRef.current as HTMLElement;
           elem.style.backgroundColor = "white";
@@ -274,7 +292,7 @@ function BlackKey({ multi }: { multi: number }) {
         <div
           className="w-[calc(69svw/80)] h-3/4 top-0 z-10"
           style={{
-            backgroundColor: isActive ? activeColor : "black",
+            backgroundColor: isActive ? (isOutOfScale ? outOfScaleColor : activeColor) : "black",
           }}
           ref={keyRef}
         ></div>
@@ -298,3 +316,42 @@ function BlackKey({ multi }: { multi: number }) {
     </div>
   );
 }
+
+function isWhiteKey(key: number): boolean {
+  return [0, 2, 4, 5, 7, 9, 11].includes(key % 12);
+}
+
+function whiteKeysBefore(key: number): number {
+  let count = 0;
+  for (let k = 21; k < key; k++) {
+    if (isWhiteKey(k)) count++;
+  }
+  return count;
+}
+
+// Shows the key range of every zone above the keys, overlapping (layered) zones in their own rows
+export function ZoneStrip({ zones }: { zones: Zone[] }) {
+  if (zones.length === 0) return null;
+
+  return (
+    <div className="relative" style={{ height: `${zones.length * 1.25}rem` }}>
+      {zones.map((zone, i) => {
+        const start = whiteKeysBefore(zone.low);
+        const end = whiteKeysBefore(zone.high + 1);
+        return (
+          <div
+            key={i}
+            className={`absolute h-5 text-xs px-1 truncate ${zoneColors[i % zoneColors.length]}`}
+            style={{
+              top: `${i * 1.25}rem`,
+              left: `calc(${start} * 69svw/52)`,
+              width: `calc(${Math.max(end - start, 1)} * 69svw/52)`,
+            }}
+          >
+            {zone.name}
+          </div>
+        );
+      })}
+    </div>
+  );
+}